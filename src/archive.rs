@@ -0,0 +1,727 @@
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use walkdir::WalkDir;
+use zip::read::ZipFile;
+use zip::result::ZipError;
+use zip::write::SimpleFileOptions;
+use zip::{AesMode, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::manifest::{sha256_hex, Manifest, ManifestFile, MANIFEST_NAME};
+use crate::store::{self, SNAPSHOT_EXTENSION};
+use crate::tarball;
+
+/// How backups are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum BackupFormat {
+    /// One zip archive per backup
+    Zip,
+    /// One zstd compressed tar archive per backup
+    #[serde(rename = "tar.zst")]
+    #[value(name = "tar.zst")]
+    TarZst,
+    /// Snapshots in the deduplicating chunk store
+    Store,
+}
+
+/// Compression method of zip backups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// No compression. The save files are encrypted and barely compress, so this is the fastest
+    Stored,
+    Deflate,
+    Zstd,
+    Bzip2,
+}
+
+/// How new backups are written. Not `Debug`, as it holds the passphrase.
+#[derive(Clone)]
+pub struct BackupOptions {
+    pub format: BackupFormat,
+    /// Compression method of zip backups, tar backups are always compressed with zstd
+    pub compression: Compression,
+    /// Compression level of zip and tar backups, the method's default if `None`
+    pub level: Option<i64>,
+    /// Passphrase the save files of zip backups are AES-256 encrypted with, `None` for no encryption
+    pub passphrase: Option<String>,
+}
+
+impl BackupFormat {
+    pub const ALL: [BackupFormat; 3] = [BackupFormat::Zip, BackupFormat::TarZst, BackupFormat::Store];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BackupFormat::Zip => "zip",
+            BackupFormat::TarZst => "tar.zst",
+            BackupFormat::Store => "store",
+        }
+    }
+
+    /// File extension of backups in this format
+    pub fn extension(self) -> &'static str {
+        match self {
+            BackupFormat::Zip => "zip",
+            BackupFormat::TarZst => "tar.zst",
+            BackupFormat::Store => SNAPSHOT_EXTENSION,
+        }
+    }
+
+    /// Format of the backup at `path`, `None` if it is not a backup
+    pub fn of_path(path: &Path) -> Option<BackupFormat> {
+        let name = path.file_name()?.to_str()?;
+        BackupFormat::ALL
+            .into_iter()
+            .find(|format| name.ends_with(&format!(".{}", format.extension())))
+    }
+}
+
+impl fmt::Display for BackupFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for BackupFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "zip" => Ok(BackupFormat::Zip),
+            "tar.zst" | "tarzst" => Ok(BackupFormat::TarZst),
+            "store" => Ok(BackupFormat::Store),
+            other => bail!("Unknown backup format {other}"),
+        }
+    }
+}
+
+impl Compression {
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::Stored => "stored",
+            Compression::Deflate => "deflate",
+            Compression::Zstd => "zstd",
+            Compression::Bzip2 => "bzip2",
+        }
+    }
+
+    fn zip_method(self) -> CompressionMethod {
+        match self {
+            Compression::Stored => CompressionMethod::Stored,
+            Compression::Deflate => CompressionMethod::Deflated,
+            Compression::Zstd => CompressionMethod::Zstd,
+            Compression::Bzip2 => CompressionMethod::Bzip2,
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "stored" | "none" => Ok(Compression::Stored),
+            "deflate" => Ok(Compression::Deflate),
+            "zstd" => Ok(Compression::Zstd),
+            "bzip2" => Ok(Compression::Bzip2),
+            other => bail!("Unknown compression method {other}"),
+        }
+    }
+}
+
+/// Compression levels supported for new backups in `format` with `compression`, `None` if no level
+/// is used (stored zip entries and the backup store).
+pub fn level_range(format: BackupFormat, compression: Compression) -> Option<RangeInclusive<i64>> {
+    match (format, compression) {
+        (BackupFormat::Store, _) | (BackupFormat::Zip, Compression::Stored) => None,
+        (BackupFormat::TarZst, _) | (BackupFormat::Zip, Compression::Zstd) => {
+            let range = zstd::compression_level_range();
+            Some((*range.start()).into()..=(*range.end()).into())
+        }
+        // Levels above 9 use zopfli
+        (BackupFormat::Zip, Compression::Deflate) => Some(1..=264),
+        (BackupFormat::Zip, Compression::Bzip2) => Some(1..=9),
+    }
+}
+
+fn format_of(backup_path: &Path) -> Result<BackupFormat> {
+    BackupFormat::of_path(backup_path)
+        .with_context(|| format!("{} is not a backup", backup_path.display()))
+}
+
+/// Backs up `source_dir` to `backup_path` as set in `options` and returns the completed manifest.
+pub fn create_backup_file(
+    options: &BackupOptions,
+    source_dir: &Path,
+    backup_path: &Path,
+    manifest: Manifest,
+) -> Result<Manifest> {
+    if options.passphrase.is_some() && options.format != BackupFormat::Zip {
+        bail!("Encryption is only supported for zip backups, not {}", options.format);
+    }
+    match options.format {
+        BackupFormat::Zip => create_zip_backup(source_dir, backup_path, manifest, options),
+        BackupFormat::TarZst => tarball::create_tar_backup(source_dir, backup_path, manifest, options.level),
+        BackupFormat::Store => store::create_snapshot(source_dir, backup_path, manifest),
+    }
+}
+
+/// Whether the save files of a backup are encrypted, so a passphrase is needed to verify or restore it
+pub fn is_encrypted(backup_path: &Path) -> Result<bool> {
+    if format_of(backup_path)? != BackupFormat::Zip {
+        return Ok(false);
+    }
+    let mut zip = zip::ZipArchive::new(File::open(backup_path)?)?;
+    for i in 0..zip.len() {
+        if zip.by_index_raw(i)?.encrypted() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Checks a backup of any format, see [`verify_zip_backup`] and [`store::verify_snapshot`].
+pub fn verify_backup(backup_path: &Path, passphrase: Option<&str>) -> Result<VerifyReport> {
+    match format_of(backup_path)? {
+        BackupFormat::Zip => verify_zip_backup(backup_path, passphrase),
+        BackupFormat::TarZst => tarball::verify_tar_backup(backup_path),
+        BackupFormat::Store => store::verify_snapshot(backup_path),
+    }
+}
+
+/// Restores the files of a backup of any format into `target_dir`.
+///
+/// The backup is verified first and a broken backup is refused unless `force` is set, in which case
+/// the files that cannot be restored are left out. Unsafe entries are skipped even with `force`.
+pub fn extract_backup(backup_path: &Path, target_dir: &Path, force: bool, passphrase: Option<&str>) -> Result<()> {
+    let report = verify_backup(backup_path, passphrase)?;
+    if !report.is_ok() {
+        if !force {
+            bail!("Backup failed verification, use --force to restore it anyway: {report}");
+        }
+        println!("Warning: restoring a backup that failed verification: {report}");
+    }
+
+    match format_of(backup_path)? {
+        BackupFormat::Zip => extract_zip_backup(backup_path, target_dir, passphrase),
+        BackupFormat::TarZst => tarball::extract_tar_backup(backup_path, target_dir),
+        BackupFormat::Store => store::extract_snapshot(backup_path, target_dir),
+    }
+}
+
+/// Zips the contents of `source_dir` into `backup_path` with the compression and encryption set in
+/// `backup_options`.
+///
+/// The file list of `manifest` is filled with the size and SHA-256 of every file, and the manifest
+/// is stored as the last entry of the archive. The manifest is never encrypted, so backups can be
+/// listed and pruned without the passphrase.
+pub fn create_zip_backup(
+    source_dir: &Path,
+    backup_path: &Path,
+    mut manifest: Manifest,
+    backup_options: &BackupOptions,
+) -> Result<Manifest> {
+    let file = File::create(backup_path)?;
+    let mut zip = ZipWriter::new(file);
+    let manifest_options = SimpleFileOptions::default()
+        .compression_method(backup_options.compression.zip_method())
+        .compression_level(backup_options.level);
+    let options = match &backup_options.passphrase {
+        Some(passphrase) => manifest_options.with_aes_encryption(AesMode::Aes256, passphrase),
+        None => manifest_options,
+    };
+
+    for entry in WalkDir::new(source_dir).min_depth(1).sort_by_file_name() {
+        let entry = entry?;
+        let name = entry_name(entry.path().strip_prefix(source_dir)?);
+
+        #[cfg(unix)]
+        let options = {
+            use std::os::unix::fs::PermissionsExt;
+            options.unix_permissions(entry.metadata()?.permissions().mode())
+        };
+
+        if entry.file_type().is_dir() {
+            zip.add_directory(name, options)?;
+        } else {
+            let data = fs::read(entry.path())?;
+            manifest.files.push(ManifestFile {
+                path: name.clone(),
+                size: data.len() as u64,
+                sha256: sha256_hex(&data),
+            });
+            zip.start_file(name, options)?;
+            zip.write_all(&data)?;
+        }
+    }
+
+    zip.start_file(MANIFEST_NAME, manifest_options)?;
+    zip.write_all(serde_json::to_string_pretty(&manifest)?.as_bytes())?;
+    zip.finish()?;
+    Ok(manifest)
+}
+
+/// Size and SHA-256 of every file in `source_dir`, in the order [`create_zip_backup`] stores them.
+pub fn hash_save_files(source_dir: &Path) -> Result<Vec<ManifestFile>> {
+    let mut files = Vec::new();
+    for entry in WalkDir::new(source_dir).min_depth(1).sort_by_file_name() {
+        let entry = entry?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let data = fs::read(entry.path())?;
+        files.push(ManifestFile {
+            path: entry_name(entry.path().strip_prefix(source_dir)?),
+            size: data.len() as u64,
+            sha256: sha256_hex(&data),
+        });
+    }
+    Ok(files)
+}
+
+/// Zip entry name of a path relative to the save folder. Zip entries always use forward slashes.
+pub fn entry_name(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Reads the manifest of a backup, `None` for backups created before manifests were added.
+pub fn read_manifest(backup_path: &Path) -> Result<Option<Manifest>> {
+    match format_of(backup_path)? {
+        BackupFormat::Zip => read_zip_manifest(backup_path),
+        BackupFormat::TarZst => tarball::read_tar_manifest(backup_path),
+        BackupFormat::Store => Ok(Some(store::read_snapshot(backup_path)?.manifest)),
+    }
+}
+
+fn read_zip_manifest(backup_path: &Path) -> Result<Option<Manifest>> {
+    let file = File::open(backup_path)?;
+    let mut zip = zip::ZipArchive::new(file)?;
+    let mut entry = match zip.by_name(MANIFEST_NAME) {
+        Ok(entry) => entry,
+        Err(ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut content = String::new();
+    entry.read_to_string(&mut content)?;
+    let manifest = serde_json::from_str(&content)
+        .with_context(|| format!("Invalid {} in {}", MANIFEST_NAME, backup_path.display()))?;
+    Ok(Some(manifest))
+}
+
+/// An entry of a backup, as listed by `show`.
+#[derive(Debug, Clone)]
+pub struct EntryInfo {
+    pub name: String,
+    pub size: u64,
+    pub is_dir: bool,
+    /// Modification time stored in the archive, `None` for snapshots, which do not keep it
+    pub modified: Option<NaiveDateTime>,
+}
+
+/// Lists the entries of a backup of any format in archive order, without reading their content.
+pub fn list_entries(backup_path: &Path) -> Result<Vec<EntryInfo>> {
+    match format_of(backup_path)? {
+        BackupFormat::Zip => list_zip_entries(backup_path),
+        BackupFormat::TarZst => tarball::list_tar_entries(backup_path),
+        BackupFormat::Store => store::list_snapshot_entries(backup_path),
+    }
+}
+
+fn list_zip_entries(backup_path: &Path) -> Result<Vec<EntryInfo>> {
+    let file = File::open(backup_path)?;
+    let mut zip = zip::ZipArchive::new(file)?;
+    let mut entries = Vec::new();
+    for i in 0..zip.len() {
+        // Raw access, so the entries of encrypted backups can be listed without the passphrase
+        let entry = zip.by_index_raw(i)?;
+        let modified = entry.last_modified().and_then(|time| {
+            NaiveDate::from_ymd_opt(time.year().into(), time.month().into(), time.day().into())?.and_hms_opt(
+                time.hour().into(),
+                time.minute().into(),
+                time.second().into(),
+            )
+        });
+        entries.push(EntryInfo { name: entry.name().to_string(), size: entry.size(), is_dir: entry.is_dir(), modified });
+    }
+    Ok(entries)
+}
+
+/// Size and SHA-256 of every save file in a backup, sorted by path.
+///
+/// The entries of zip backups are read and hashed, so backups without a manifest can be compared as
+/// well. The other formats always have a manifest and its file list is used.
+pub fn backup_files(backup_path: &Path, passphrase: Option<&str>) -> Result<Vec<ManifestFile>> {
+    if format_of(backup_path)? != BackupFormat::Zip {
+        let manifest = read_manifest(backup_path)?
+            .with_context(|| format!("{} has no manifest", backup_path.display()))?;
+        return Ok(manifest.files);
+    }
+
+    let file = File::open(backup_path)?;
+    let mut zip = zip::ZipArchive::new(file)?;
+    let mut files = Vec::new();
+    for i in 0..zip.len() {
+        let mut entry = open_entry(&mut zip, i, passphrase).map_err(|e| match passphrase_error(&e) {
+            Some(message) => anyhow!(message),
+            None => e.into(),
+        })?;
+        if entry.is_dir() || entry.name() == MANIFEST_NAME || entry_path(&entry).is_err() {
+            continue;
+        }
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data).with_context(|| format!("Failed to read {}", entry.name()))?;
+        files.push(ManifestFile { path: entry.name().to_string(), size: data.len() as u64, sha256: sha256_hex(&data) });
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+/// Path of an archive entry relative to the extraction directory, or why the entry must not be extracted.
+///
+/// Symlinks and names that are absolute or climb out of the extraction directory are rejected, so a
+/// crafted archive cannot write outside the save folder.
+fn entry_path<R: Read>(entry: &ZipFile<'_, R>) -> Result<PathBuf, &'static str> {
+    if entry.is_symlink() {
+        return Err("symlink");
+    }
+    check_entry_name(entry.name())?;
+    entry.enclosed_name().ok_or("path escapes the target directory")
+}
+
+/// Rejects entry names that are absolute, on Unix or Windows, or contain backslashes, which Windows
+/// treats as separators.
+pub fn check_entry_name(name: &str) -> Result<(), &'static str> {
+    let has_drive = name.len() >= 2 && name.as_bytes()[1] == b':' && name.as_bytes()[0].is_ascii_alphabetic();
+    if name.starts_with('/') || name.starts_with('\\') || has_drive {
+        return Err("absolute path");
+    }
+    if name.contains('\\') {
+        return Err("backslash in path");
+    }
+    Ok(())
+}
+
+/// Checks that every file in the backup was extracted to `dir` with the expected size.
+///
+/// Snapshots are skipped, their files are checked against their hashes while they are restored.
+pub fn verify_extracted(backup_path: &Path, dir: &Path) -> Result<()> {
+    match format_of(backup_path)? {
+        BackupFormat::Zip => {}
+        BackupFormat::TarZst => return tarball::verify_tar_extracted(backup_path, dir),
+        BackupFormat::Store => return Ok(()),
+    }
+
+    let file = File::open(backup_path)?;
+    let mut zip = zip::ZipArchive::new(file)?;
+
+    for i in 0..zip.len() {
+        let file = zip.by_index_raw(i)?;
+        if file.is_dir() || file.name() == MANIFEST_NAME {
+            continue;
+        }
+        let Ok(relative) = entry_path(&file) else {
+            continue;
+        };
+        let path = dir.join(relative);
+        let size = fs::metadata(&path)
+            .with_context(|| format!("{} missing after extraction", file.name()))?
+            .len();
+        if size != file.size() {
+            bail!("{} has size {} after extraction, expected {}", file.name(), size, file.size());
+        }
+    }
+
+    Ok(())
+}
+
+/// Result of checking a backup against the hashes in its manifest.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Whether the backup has a manifest. Without one only the zip CRCs can be checked.
+    pub has_manifest: bool,
+    /// Number of save files checked
+    pub checked: usize,
+    /// Files listed in the manifest but not in the archive
+    pub missing: Vec<String>,
+    /// Files in the archive but not listed in the manifest
+    pub extra: Vec<String>,
+    /// Files that cannot be read or whose size or hash differs from the manifest
+    pub corrupted: Vec<(String, String)>,
+    /// Entries that would be written outside the save folder, never extracted
+    pub rejected: Vec<(String, String)>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.corrupted.is_empty() && self.rejected.is_empty()
+    }
+
+    /// Compares a file read from a backup with its manifest entry, taking the entry out of `expected`.
+    pub fn check_file(&mut self, expected: &mut BTreeMap<String, &ManifestFile>, name: String, data: &[u8]) {
+        match expected.remove(&name) {
+            None => self.extra.push(name),
+            Some(file) if file.size != data.len() as u64 => {
+                let reason = format!("size {} instead of {}", data.len(), file.size);
+                self.corrupted.push((name, reason));
+            }
+            Some(file) if file.sha256 != sha256_hex(data) => {
+                self.corrupted.push((name, "SHA-256 mismatch".to_string()));
+            }
+            Some(_) => {}
+        }
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_ok() {
+            write!(f, "OK, {} files intact", self.checked)?;
+            if !self.has_manifest {
+                write!(f, " (no manifest, only zip checksums checked)")?;
+            }
+            return Ok(());
+        }
+
+        write!(
+            f,
+            "BROKEN, {} missing, {} extra, {} corrupted, {} rejected",
+            self.missing.len(),
+            self.extra.len(),
+            self.corrupted.len(),
+            self.rejected.len()
+        )?;
+        for name in &self.missing {
+            write!(f, "\n  missing:   {name}")?;
+        }
+        for name in &self.extra {
+            write!(f, "\n  extra:     {name}")?;
+        }
+        for (name, reason) in &self.corrupted {
+            write!(f, "\n  corrupted: {name} ({reason})")?;
+        }
+        for (name, reason) in &self.rejected {
+            write!(f, "\n  rejected:  {name} ({reason})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Opens entry `i` of a zip backup, decrypting it with `passphrase` if it is encrypted.
+fn open_entry<'a>(
+    zip: &'a mut ZipArchive<File>,
+    i: usize,
+    passphrase: Option<&str>,
+) -> Result<ZipFile<'a, File>, ZipError> {
+    match passphrase {
+        Some(passphrase) => zip.by_index_decrypt(i, passphrase.as_bytes()),
+        None => zip.by_index(i),
+    }
+}
+
+/// Error message if `e` is caused by a missing or wrong passphrase
+fn passphrase_error(e: &ZipError) -> Option<&'static str> {
+    match e {
+        ZipError::InvalidPassword => Some("Wrong passphrase"),
+        ZipError::UnsupportedArchive(message) if *message == ZipError::PASSWORD_REQUIRED => {
+            Some("Backup is encrypted, a passphrase is needed")
+        }
+        _ => None,
+    }
+}
+
+/// Reads every entry of a backup and compares it with the size and SHA-256 recorded in the manifest.
+/// Encrypted entries are decrypted with `passphrase`.
+pub fn verify_zip_backup(backup_path: &Path, passphrase: Option<&str>) -> Result<VerifyReport> {
+    let manifest = read_zip_manifest(backup_path)?;
+    let file = File::open(backup_path)?;
+    let mut zip = zip::ZipArchive::new(file)?;
+
+    let mut expected: BTreeMap<String, _> = manifest
+        .iter()
+        .flat_map(|manifest| &manifest.files)
+        .map(|file| (file.path.clone(), file))
+        .collect();
+    let mut report = VerifyReport { has_manifest: manifest.is_some(), ..Default::default() };
+
+    for i in 0..zip.len() {
+        let mut entry = match open_entry(&mut zip, i, passphrase) {
+            Ok(entry) => entry,
+            Err(e) => {
+                if let Some(message) = passphrase_error(&e) {
+                    bail!(message);
+                }
+                report.corrupted.push((format!("entry #{i}"), e.to_string()));
+                continue;
+            }
+        };
+        let name = entry.name().to_string();
+        if let Err(reason) = entry_path(&entry) {
+            report.rejected.push((name, reason.to_string()));
+            continue;
+        }
+        if entry.is_dir() || name == MANIFEST_NAME {
+            continue;
+        }
+        report.checked += 1;
+
+        // Reading the whole entry also checks the zip CRC
+        let mut data = Vec::new();
+        if let Err(e) = entry.read_to_end(&mut data) {
+            report.corrupted.push((name, e.to_string()));
+            continue;
+        }
+
+        if manifest.is_none() {
+            continue;
+        }
+        report.check_file(&mut expected, name, &data);
+    }
+
+    report.missing.extend(expected.into_keys());
+    Ok(report)
+}
+
+/// Extracts the save files of a backup into `target_dir`, leaving out the manifest.
+///
+/// Entries that would end up outside `target_dir` are reported and skipped. Verification is left to
+/// [`extract_backup`].
+pub fn extract_zip_backup(backup_path: &Path, target_dir: &Path, passphrase: Option<&str>) -> Result<()> {
+    let file = File::open(backup_path)?;
+    let mut zip = zip::ZipArchive::new(file)?;
+
+    for i in 0..zip.len() {
+        let mut file = open_entry(&mut zip, i, passphrase)?;
+        if file.name() == MANIFEST_NAME {
+            continue;
+        }
+        let outpath = match entry_path(&file) {
+            Ok(relative) => target_dir.join(relative),
+            Err(reason) => {
+                println!("Skipping unsafe entry {} ({reason})", file.name());
+                continue;
+            }
+        };
+
+        if file.is_dir() {
+            fs::create_dir_all(&outpath)?;
+        } else {
+            if let Some(p) = outpath.parent() {
+                if !p.exists() {
+                    fs::create_dir_all(p)?;
+                }
+            }
+            let mut outfile = File::create(&outpath)?;
+            io::copy(&mut file, &mut outfile)?;
+        }
+
+        // Get and Set permissions, without setuid, setgid or sticky bits
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if let Some(mode) = file.unix_mode() {
+                fs::set_permissions(&outpath, fs::Permissions::from_mode(mode & 0o777))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty directory for a test, cleared first in case an earlier run left it behind
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("acnh-backup-test-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Every file below `dir`, relative to it
+    fn files_in(dir: &Path) -> Vec<String> {
+        WalkDir::new(dir)
+            .min_depth(1)
+            .sort_by_file_name()
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| !entry.file_type().is_dir())
+            .map(|entry| entry_name(entry.path().strip_prefix(dir).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn entry_names() {
+        let cases = [
+            ("main.dat", Ok(())),
+            ("Villager0/personal.dat", Ok(())),
+            ("/etc/passwd", Err("absolute path")),
+            ("\\Windows\\evil", Err("absolute path")),
+            ("C:/evil", Err("absolute path")),
+            ("c:evil", Err("absolute path")),
+            ("..\\evil", Err("backslash in path")),
+            ("Villager0\\personal.dat", Err("backslash in path")),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(check_entry_name(name), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn zip_unsafe_entries_are_rejected() {
+        let root = test_dir("zip-unsafe");
+        let outside = root.join("outside");
+        let backup = root.join("backup.zip");
+        let unsafe_names = [
+            "../outside".to_string(),
+            "Villager0/../../outside".to_string(),
+            outside.to_string_lossy().into_owned(),
+            "C:/outside".to_string(),
+            "..\\outside".to_string(),
+            "link".to_string(),
+        ];
+
+        let options = SimpleFileOptions::default();
+        let mut zip = ZipWriter::new(File::create(&backup).unwrap());
+        zip.start_file("main.dat", options).unwrap();
+        zip.write_all(b"save").unwrap();
+        for name in &unsafe_names[..unsafe_names.len() - 1] {
+            zip.start_file(name.as_str(), options).unwrap();
+            zip.write_all(b"evil").unwrap();
+        }
+        zip.add_symlink("link", outside.to_string_lossy(), options).unwrap();
+        zip.finish().unwrap();
+
+        let report = verify_backup(&backup, None).unwrap();
+        let rejected: Vec<&str> = report.rejected.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(rejected, unsafe_names);
+        assert_eq!(report.checked, 1);
+
+        let target = root.join("target");
+        assert!(extract_backup(&backup, &target, false, None).is_err());
+        extract_backup(&backup, &target, true, None).unwrap();
+        assert_eq!(files_in(&root), ["backup.zip", "target/main.dat"]);
+        assert_eq!(fs::read(target.join("main.dat")).unwrap(), b"save");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,166 @@
+//! The backups in the backup directory: listing them and resolving the selectors of the commands.
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use regex::Regex;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::archive::{read_manifest, BackupFormat};
+use crate::emulator::SaveLocation;
+use crate::manifest::Manifest;
+use crate::pins::{Pin, Pins};
+
+/// Custom name used for the snapshots taken automatically before a restore
+pub const PRE_RESTORE_NAME: &str = "pre-restore";
+
+/// A backup zip or store snapshot found in the backup directory.
+pub struct BackupEntry {
+    pub filename: String,
+    pub path: PathBuf,
+    /// Custom name from the manifest or the filename, `None` if neither is available
+    pub name: Option<String>,
+    /// Creation time from the manifest or the filename, falling back to the file modification time
+    pub created: DateTime<Local>,
+    /// Size of the backup file in bytes, for snapshots without the chunks (`prune` adds them)
+    pub size: u64,
+    /// `None` for backups created before manifests were added
+    pub manifest: Option<Manifest>,
+    /// Set for pinned backups, which are never pruned or deleted
+    pub pin: Option<Pin>,
+}
+
+impl BackupEntry {
+    /// Whether this backup is a safety snapshot taken automatically before a restore
+    pub fn is_pre_restore(&self) -> bool {
+        self.name.as_deref() == Some(PRE_RESTORE_NAME)
+    }
+
+    /// Identifies the save the backup was taken from: the user id from the manifest, or the save
+    /// folder id the file name starts with
+    pub fn island_key(&self) -> String {
+        if let Some(user_id) = self.manifest.as_ref().and_then(|m| m.user_id.clone()) {
+            return user_id;
+        }
+        self.filename
+            .split('_')
+            .next()
+            .filter(|prefix| prefix.len() == 16 && prefix.chars().all(|c| c.is_ascii_hexdigit()))
+            .unwrap_or("unknown")
+            .to_string()
+    }
+
+    /// Whether the backup was taken from `save`: by the user id if both are known, else by the save
+    /// folder recorded in the manifest, or the save folder id the file name starts with
+    pub fn is_from(&self, save: &SaveLocation) -> bool {
+        match &self.manifest {
+            Some(manifest) => match (manifest.user_id(), save.user_id) {
+                (Some(from), Some(to)) => from == to,
+                _ => manifest.source_dir == save.dir,
+            },
+            None => self.filename.split('_').next() == save.dir.file_name().and_then(|name| name.to_str()),
+        }
+    }
+
+    /// Name shown in the backup lists, with a marker for pinned backups
+    pub fn display_name(&self) -> String {
+        let name = match &self.name {
+            Some(name) => format!("ACNH {} {}", name, self.created.format("%Y-%m-%d %H:%M:%S")),
+            None => self.filename.clone(),
+        };
+        match &self.pin {
+            Some(_) => format!("{name} [pinned]"),
+            None => name,
+        }
+    }
+}
+
+/// Lists all backups in `backup_dir`, newest first.
+///
+/// Name and creation time are taken from the manifest inside the archive, the filename is only
+/// parsed for backups without a manifest.
+pub fn list_backups(backup_dir: &Path) -> Result<Vec<BackupEntry>> {
+    // Define a regex pattern to match the backup file format
+    let re = Regex::new(r"^[0-9a-fA-F]{16}_(.+)_(\d{4}-\d{2}-\d{2})_(\d{2}-\d{2}-\d{2})(?:-\d+)?\.(?:zip|tar\.zst|snapshot)$").unwrap();
+
+    if !backup_dir.exists() {
+        return Ok(Vec::new());
+    }
+    // A broken pin index is an error, otherwise pinned backups could be pruned
+    let pins = Pins::load(backup_dir)?;
+
+    let mut backups: Vec<BackupEntry> = fs::read_dir(backup_dir)
+        .context("Failed to read backup directory")?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let path = entry.path();
+            if !path.is_file() || BackupFormat::of_path(&path).is_none() {
+                return None;
+            }
+            let filename = path.file_name()?.to_string_lossy().to_string();
+            let metadata = entry.metadata().ok()?;
+            let modified: DateTime<Local> = metadata.modified().ok()?.into();
+            // A corrupt archive is still listed, it fails later when it is used
+            let manifest = read_manifest(&path).ok().flatten();
+
+            let (name, created) = match (&manifest, re.captures(&filename)) {
+                (Some(manifest), _) => (Some(manifest.name.clone()), manifest.created.with_timezone(&Local)),
+                (None, Some(captures)) => {
+                    // Extract the custom name and datetime
+                    let datetime_str = format!("{} {}", &captures[2], &captures[3]);
+                    let created = NaiveDateTime::parse_from_str(&datetime_str, "%Y-%m-%d %H-%M-%S")
+                        .ok()
+                        .and_then(|dt| Local.from_local_datetime(&dt).earliest())
+                        .unwrap_or(modified);
+                    (Some(captures[1].to_string()), created)
+                }
+                (None, None) => (None, modified),
+            };
+
+            let pin = pins.get(&filename).cloned();
+            Some(BackupEntry { filename, path, name, created, size: metadata.len(), manifest, pin })
+        })
+        .collect();
+
+    backups.sort_by_key(|b| std::cmp::Reverse(b.created));
+    Ok(backups)
+}
+
+/// Resolves a backup selector to a backup in `backup_dir`.
+/// The selector is either `latest`, a 1-based number as printed by `list`, or a file name.
+/// `latest` skips pre-restore snapshots.
+pub fn find_backup(backup_dir: &Path, selector: &str) -> Result<BackupEntry> {
+    let mut backups = list_backups(backup_dir)?;
+    if backups.is_empty() {
+        bail!("No backups found in {}", backup_dir.display());
+    }
+
+    if selector == "latest" {
+        return backups
+            .into_iter()
+            .find(|b| !b.is_pre_restore())
+            .context("Only pre-restore snapshots found, use `undo` to restore them");
+    }
+    if let Ok(number) = selector.parse::<usize>() {
+        if number == 0 || number > backups.len() {
+            bail!("Backup number {} out of range (1-{})", number, backups.len());
+        }
+        return Ok(backups.remove(number - 1));
+    }
+    match backups.iter().position(|b| b.filename == selector) {
+        Some(i) => Ok(backups.remove(i)),
+        None => bail!("No backup named {} in {}", selector, backup_dir.display()),
+    }
+}
+
+/// Like [`find_backup`], but `latest` is the newest backup taken from `save`, so it never picks the
+/// island of another profile.
+pub fn find_backup_of(backup_dir: &Path, selector: &str, save: &SaveLocation) -> Result<BackupEntry> {
+    if selector != "latest" {
+        return find_backup(backup_dir, selector);
+    }
+    list_backups(backup_dir)?
+        .into_iter()
+        .find(|b| !b.is_pre_restore() && b.is_from(save))
+        .with_context(|| format!("No backup of the save {} found", save.dir.display()))
+}
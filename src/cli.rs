@@ -0,0 +1,194 @@
+use clap::{ArgGroup, Parser, Subcommand};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::archive::{BackupFormat, Compression};
+use crate::emulator::Emulator;
+use crate::running::IfRunning;
+use crate::schedule::parse_cron;
+
+/// Backup and restore tool for Animal Crossing: New Horizons saves.
+///
+/// Without a subcommand the interactive menu is started.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Save directory to back up and restore into [env: ACNH_BACKUP_SAVE_DIR]
+    #[arg(long, global = true, value_name = "DIR")]
+    pub save_dir: Option<PathBuf>,
+
+    /// Emulator whose save is used, auto-detected if not set [env: ACNH_BACKUP_EMULATOR]
+    #[arg(long, global = true, value_enum)]
+    pub emulator: Option<Emulator>,
+
+    /// User profile whose save is used, by name or user id [env: ACNH_BACKUP_PROFILE]
+    #[arg(long, global = true, value_name = "NAME|ID")]
+    pub profile: Option<String>,
+
+    /// Directory the backups are stored in [env: ACNH_BACKUP_BACKUP_DIR]
+    #[arg(long, global = true, value_name = "DIR")]
+    pub backup_dir: Option<PathBuf>,
+
+    /// Format new backups are stored in [env: ACNH_BACKUP_FORMAT]
+    #[arg(long, global = true, value_enum)]
+    pub format: Option<BackupFormat>,
+
+    /// Compression method of zip backups [env: ACNH_BACKUP_COMPRESSION]
+    #[arg(long, global = true, value_enum)]
+    pub compression: Option<Compression>,
+
+    /// Compression level of zip and tar.zst backups [env: ACNH_BACKUP_LEVEL]
+    #[arg(long, global = true, allow_negative_numbers = true)]
+    pub level: Option<i64>,
+
+    /// Encrypt new backups with a passphrase, zip backups only [env: ACNH_BACKUP_ENCRYPT]
+    #[arg(long, global = true)]
+    pub encrypt: bool,
+
+    /// File holding the backup passphrase, asked for if not set [env: ACNH_BACKUP_KEY_FILE]
+    #[arg(long, global = true, value_name = "FILE")]
+    pub key_file: Option<PathBuf>,
+
+    /// What to do when the emulator is running before a backup or restore, not used in watch and schedule mode [env: ACNH_BACKUP_IF_RUNNING]
+    #[arg(long, global = true, value_enum)]
+    pub if_running: Option<IfRunning>,
+
+    /// Config file to use instead of the default location [env: ACNH_BACKUP_CONFIG]
+    #[arg(long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Create a new backup of the save directory
+    Backup {
+        /// Custom name stored in the backup file name
+        #[arg(short, long, default_value = "Backup")]
+        name: String,
+        /// Create the backup even if the save is unchanged since the last backup
+        #[arg(long)]
+        allow_duplicate: bool,
+    },
+    /// Watch the save directory and create a backup whenever the game saves
+    Watch {
+        /// Custom name of the automatic backups
+        #[arg(short, long, default_value = "auto")]
+        name: String,
+        /// Seconds without changes after which the emulator is considered done writing
+        #[arg(long, default_value_t = 10, value_name = "SECS")]
+        quiet: u64,
+    },
+    /// Create backups on a timer, skipping runs where the save did not change
+    #[command(group(ArgGroup::new("timer").required(true).args(["every", "cron"])))]
+    Schedule {
+        /// Interval between backups, e.g. `30m`, `1h` or `1day`
+        #[arg(long, value_name = "INTERVAL", value_parser = humantime::parse_duration)]
+        every: Option<Duration>,
+        /// Cron expression, e.g. `0 */2 * * *` for every two hours. The seconds field is optional
+        #[arg(long, value_name = "EXPR", value_parser = parse_cron)]
+        cron: Option<Box<cron::Schedule>>,
+        /// Custom name of the scheduled backups
+        #[arg(short, long, default_value = "scheduled")]
+        name: String,
+        /// Seconds without changes a run waits for before backing up, like in watch mode
+        #[arg(long, default_value_t = 10, value_name = "SECS")]
+        quiet: u64,
+    },
+    /// Restore a backup into the save directory
+    Restore {
+        /// Backup to restore: number from `list`, file name or `latest` (the newest backup of this save)
+        backup: String,
+        /// Restore even if the backup fails verification
+        #[arg(long)]
+        force: bool,
+        /// Only restore these game files, e.g. `Villager1/personal.dat`, leaving the rest of the save untouched
+        #[arg(long, value_name = "FILE", num_args = 1..)]
+        only: Vec<String>,
+        /// Restore the backup even if it was taken from another user's save
+        #[arg(long)]
+        any_user: bool,
+    },
+    /// Undo the last restore by restoring the snapshot taken before it
+    Undo,
+    /// Check backups against the file hashes recorded in their manifest
+    Verify {
+        /// Backup to verify: number from `list`, file name or `latest`. Verifies all backups if omitted
+        backup: Option<String>,
+    },
+    /// List all backups, newest first
+    List,
+    /// Show which save files were added, removed or modified between two backups
+    Diff {
+        /// Older backup: number from `list`, file name or `latest`
+        from: String,
+        /// Newer backup: number from `list`, file name or `latest`. Compares with the current save if omitted
+        to: Option<String>,
+    },
+    /// Show the details and entries of a backup
+    Show {
+        /// Backup to show: number from `list`, file name or `latest`
+        backup: String,
+    },
+    /// Unpack a backup into a directory, without touching the save directory
+    Extract {
+        /// Backup to extract: number from `list`, file name or `latest`
+        backup: String,
+        /// Directory to extract into, must be empty or not exist yet
+        #[arg(long, value_name = "DIR")]
+        to: PathBuf,
+        /// Extract even if the backup fails verification
+        #[arg(long)]
+        force: bool,
+    },
+    /// Decrypt the game files of the save or a backup and check their checksums
+    Inspect {
+        /// Backup to inspect: number from `list`, file name or `latest`. Inspects the current save if omitted
+        backup: Option<String>,
+    },
+    /// Delete a backup. Pinned backups have to be unpinned first
+    Delete {
+        /// Backup to delete: number from `list`, file name or `latest`
+        backup: String,
+    },
+    /// Pin a backup so it is never pruned or deleted
+    Pin {
+        /// Backup to pin: number from `list`, file name or `latest`
+        backup: String,
+        /// Why the backup is kept, shown by `prune --dry-run`
+        #[arg(short, long)]
+        note: Option<String>,
+    },
+    /// Remove the pin of a backup
+    Unpin {
+        /// Backup to unpin: number from `list`, file name or `latest`
+        backup: String,
+    },
+    /// Delete old backups according to the retention policy in the config file
+    Prune {
+        /// Only show which backups would be deleted and why
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Delete the chunks of the backup store that no snapshot refers to any more
+    Gc {
+        /// Only show how much would be freed
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// List every game save of the installed emulators
+    Saves,
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Print the resolved paths and where each one came from
+    Show,
+}
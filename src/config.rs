@@ -0,0 +1,376 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::archive::{level_range, BackupFormat, BackupOptions, Compression};
+use crate::cli::Cli;
+use crate::emulator::{Emulator, GameSave, SaveLocation};
+use crate::retention::RetentionConfig;
+use crate::running::IfRunning;
+use crate::ryujinx;
+
+/// Environment variable overriding the save directory
+pub const SAVE_DIR_ENV: &str = "ACNH_BACKUP_SAVE_DIR";
+/// Environment variable overriding the backup directory
+pub const BACKUP_DIR_ENV: &str = "ACNH_BACKUP_BACKUP_DIR";
+/// Environment variable selecting the emulator
+pub const EMULATOR_ENV: &str = "ACNH_BACKUP_EMULATOR";
+/// Environment variable selecting the user profile
+pub const PROFILE_ENV: &str = "ACNH_BACKUP_PROFILE";
+/// Environment variable selecting the backup format
+pub const FORMAT_ENV: &str = "ACNH_BACKUP_FORMAT";
+/// Environment variable selecting the compression method of zip backups
+pub const COMPRESSION_ENV: &str = "ACNH_BACKUP_COMPRESSION";
+/// Environment variable setting the compression level
+pub const LEVEL_ENV: &str = "ACNH_BACKUP_LEVEL";
+/// Environment variable enabling encrypted backups
+pub const ENCRYPT_ENV: &str = "ACNH_BACKUP_ENCRYPT";
+/// Environment variable setting the file the backup passphrase is read from
+pub const KEY_FILE_ENV: &str = "ACNH_BACKUP_KEY_FILE";
+/// Environment variable selecting what happens when the emulator is running
+pub const IF_RUNNING_ENV: &str = "ACNH_BACKUP_IF_RUNNING";
+/// Environment variable overriding the config file location
+pub const CONFIG_ENV: &str = "ACNH_BACKUP_CONFIG";
+
+/// Contents of the TOML config file. Every key is optional.
+///
+/// ```toml
+/// emulator = "ryujinx"
+/// profile = "Tom"
+/// save_dir = "/opt/ryujinx-portable/bis/user/save/0000000000000001"
+/// backup_dir = "/mnt/nas/acnh-backups"
+/// format = "zip"
+/// compression = "stored"
+/// encrypt = true
+/// key_file = "/home/tom/.config/acnh-backup/passphrase"
+/// if_running = "wait"
+///
+/// [retention]
+/// keep_last = 10
+/// ```
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct ConfigFile {
+    pub emulator: Option<Emulator>,
+    /// Name or user id of the Switch user profile whose save is used
+    pub profile: Option<String>,
+    pub save_dir: Option<PathBuf>,
+    pub backup_dir: Option<PathBuf>,
+    pub format: Option<BackupFormat>,
+    pub compression: Option<Compression>,
+    /// Compression level, the method's default if not set
+    pub level: Option<i64>,
+    /// Encrypt new backups with a passphrase
+    pub encrypt: Option<bool>,
+    /// File holding the passphrase, it is asked for if not set
+    pub key_file: Option<PathBuf>,
+    /// What to do when the emulator is running before a backup or restore
+    pub if_running: Option<IfRunning>,
+    /// Which old backups `prune` deletes, see [`RetentionConfig`]
+    pub retention: RetentionConfig,
+}
+
+/// Where a resolved setting came from.
+#[derive(Debug, Clone)]
+pub enum Source {
+    Flag(&'static str),
+    Env(&'static str),
+    ConfigFile(PathBuf),
+    Detected,
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Flag(flag) => write!(f, "flag {flag}"),
+            Source::Env(var) => write!(f, "env {var}"),
+            Source::ConfigFile(path) => write!(f, "config {}", path.display()),
+            Source::Detected => write!(f, "detected"),
+            Source::Default => write!(f, "default"),
+        }
+    }
+}
+
+/// A setting together with the place it was read from.
+#[derive(Debug, Clone)]
+pub struct Resolved<T> {
+    pub value: T,
+    pub source: Source,
+}
+
+/// Settings resolved from flags, environment, config file and built-in defaults, in that order.
+#[derive(Debug)]
+pub struct Config {
+    /// Location of the config file, whether it exists or not
+    pub path: PathBuf,
+    pub file_loaded: bool,
+    /// Emulator owning the save directory
+    pub emulator: Resolved<Emulator>,
+    /// Profile selector restricting save detection, if set
+    pub profile: Option<Resolved<String>>,
+    /// Switch user profile owning the save directory, if known
+    pub user_id: Option<u128>,
+    /// `None` when no save directory is configured and detection found none or several ACNH saves
+    pub save_dir: Option<Resolved<PathBuf>>,
+    pub backup_dir: Resolved<PathBuf>,
+    /// Format new backups are stored in
+    pub format: Resolved<BackupFormat>,
+    /// Compression method of zip backups
+    pub compression: Resolved<Compression>,
+    pub level: Option<Resolved<i64>>,
+    /// Whether new backups are encrypted
+    pub encrypt: Resolved<bool>,
+    /// File the backup passphrase is read from instead of asking for it
+    pub key_file: Option<Resolved<PathBuf>>,
+    /// What to do when the emulator of the save is running before a backup or restore
+    pub if_running: Resolved<IfRunning>,
+    /// ACNH saves found in the emulator data folders, only filled when no save directory is configured
+    pub detected_saves: Vec<GameSave>,
+    /// Retention policies from the config file, empty if none are configured
+    pub retention: RetentionConfig,
+}
+
+impl Config {
+    pub fn load(cli: &Cli) -> Result<Config> {
+        let (path, explicit) = match (&cli.config, env::var_os(CONFIG_ENV).filter(|v| !v.is_empty())) {
+            (Some(path), _) => (path.clone(), true),
+            (None, Some(path)) => (PathBuf::from(path), true),
+            (None, None) => (default_config_path(), false),
+        };
+
+        let file_loaded = path.exists();
+        let file = if file_loaded {
+            read_config_file(&path)?
+        } else if explicit {
+            anyhow::bail!("Config file {} does not exist", path.display());
+        } else {
+            ConfigFile::default()
+        };
+
+        let backup_dir = resolve(cli.backup_dir.clone(), "--backup-dir", BACKUP_DIR_ENV, file.backup_dir, &path)?
+            .unwrap_or_else(|| Resolved { value: default_backup_dir(), source: Source::Default });
+        let format = resolve(cli.format, "--format", FORMAT_ENV, file.format, &path)?
+            .unwrap_or(Resolved { value: BackupFormat::Zip, source: Source::Default });
+        let compression = resolve(cli.compression, "--compression", COMPRESSION_ENV, file.compression, &path)?
+            .unwrap_or(Resolved { value: Compression::Deflate, source: Source::Default });
+        let level = resolve(cli.level, "--level", LEVEL_ENV, file.level, &path)?;
+        let encrypt = resolve(cli.encrypt.then_some(true), "--encrypt", ENCRYPT_ENV, file.encrypt, &path)?
+            .unwrap_or(Resolved { value: false, source: Source::Default });
+        if encrypt.value && format.value != BackupFormat::Zip {
+            anyhow::bail!(
+                "Encryption ({}) is only supported for zip backups, not {} ({})",
+                encrypt.source,
+                format.value,
+                format.source
+            );
+        }
+        if let (Some(level), Some(range)) = (&level, level_range(format.value, compression.value)) {
+            if !range.contains(&level.value) {
+                let target = match format.value {
+                    BackupFormat::Zip => format!("{} zip", compression.value),
+                    format => format.to_string(),
+                };
+                anyhow::bail!(
+                    "Compression level {} ({}) is not supported for {target} backups, use {} to {}",
+                    level.value,
+                    level.source,
+                    range.start(),
+                    range.end()
+                );
+            }
+        }
+        let key_file = resolve(cli.key_file.clone(), "--key-file", KEY_FILE_ENV, file.key_file, &path)?;
+        let if_running = resolve(cli.if_running, "--if-running", IF_RUNNING_ENV, file.if_running, &path)?
+            .unwrap_or(Resolved { value: IfRunning::Refuse, source: Source::Default });
+        let emulator = resolve(cli.emulator, "--emulator", EMULATOR_ENV, file.emulator, &path)?;
+        let profile = resolve(cli.profile.clone(), "--profile", PROFILE_ENV, file.profile, &path)?;
+
+        let mut detected_saves = Vec::new();
+        let (save_dir, emulator) = match resolve(cli.save_dir.clone(), "--save-dir", SAVE_DIR_ENV, file.save_dir, &path)? {
+            Some(save_dir) => {
+                let emulator = emulator.unwrap_or_else(|| Resolved {
+                    value: Emulator::guess_from_path(&save_dir.value),
+                    source: Source::Detected,
+                });
+                (Some(save_dir), emulator)
+            }
+            None => {
+                detected_saves = detect_acnh_saves(
+                    emulator.as_ref().map(|e| e.value),
+                    profile.as_ref().map(|p| p.value.as_str()),
+                );
+                match (detected_saves.as_slice(), emulator) {
+                    ([save], emulator) => (
+                        Some(Resolved { value: save.path.clone(), source: Source::Detected }),
+                        emulator.unwrap_or(Resolved { value: save.emulator, source: Source::Detected }),
+                    ),
+                    ([], None) => (
+                        Some(Resolved { value: default_save_dir(), source: Source::Default }),
+                        Resolved { value: Emulator::Ryujinx, source: Source::Default },
+                    ),
+                    (_, emulator) => (
+                        None,
+                        emulator.unwrap_or(Resolved { value: Emulator::Ryujinx, source: Source::Default }),
+                    ),
+                }
+            }
+        };
+
+        let user_id = match (detected_saves.as_slice(), &save_dir) {
+            ([save], _) => Some(save.user_id),
+            (_, Some(save_dir)) => emulator.value.read_save_user(&save_dir.value),
+            _ => None,
+        };
+
+        Ok(Config {
+            path,
+            file_loaded,
+            emulator,
+            profile,
+            user_id,
+            save_dir,
+            backup_dir,
+            format,
+            compression,
+            level,
+            encrypt,
+            key_file,
+            if_running,
+            detected_saves,
+            retention: file.retention,
+        })
+    }
+
+    /// Save folder that gets backed up and restored into, and the emulator it belongs to
+    pub fn save(&self) -> Result<SaveLocation> {
+        match &self.save_dir {
+            Some(save_dir) => Ok(SaveLocation {
+                dir: save_dir.value.clone(),
+                emulator: self.emulator.value,
+                user_id: self.user_id,
+            }),
+            None if self.detected_saves.is_empty() => anyhow::bail!(
+                "No ACNH save found for {}{}, set it with --save-dir, {} or `save_dir` in {}",
+                self.emulator.value,
+                match &self.profile {
+                    Some(profile) => format!(" and profile {}", profile.value),
+                    None => String::new(),
+                },
+                SAVE_DIR_ENV,
+                self.path.display()
+            ),
+            None => {
+                let candidates: Vec<String> = self
+                    .detected_saves
+                    .iter()
+                    .map(|save| format!("  {:<8} {}  {}", save.emulator, save.profile_label(), save.path.display()))
+                    .collect();
+                anyhow::bail!(
+                    "Found {} ACNH saves, choose one with --emulator, --profile or --save-dir, {} or `save_dir` in {}:\n{}",
+                    candidates.len(),
+                    SAVE_DIR_ENV,
+                    self.path.display(),
+                    candidates.join("\n")
+                )
+            }
+        }
+    }
+
+    /// Directory the backup zips are stored in
+    pub fn backup_dir(&self) -> &Path {
+        &self.backup_dir.value
+    }
+
+    /// Compression level of new backups, `None` for the default or if the format and method use none
+    pub fn level(&self) -> Option<i64> {
+        level_range(self.format.value, self.compression.value).and(self.level.as_ref().map(|level| level.value))
+    }
+
+    /// Format and compression of new backups, encrypted with `passphrase` if given
+    pub fn backup_options(&self, passphrase: Option<String>) -> BackupOptions {
+        BackupOptions {
+            format: self.format.value,
+            compression: self.compression.value,
+            level: self.level(),
+            passphrase,
+        }
+    }
+}
+
+fn read_config_file(path: &Path) -> Result<ConfigFile> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    toml::from_str(&content).with_context(|| format!("Failed to parse config file {}", path.display()))
+}
+
+/// Picks the first value set by flag, environment or config file.
+fn resolve<T>(
+    flag: Option<T>,
+    flag_name: &'static str,
+    env_var: &'static str,
+    file_value: Option<T>,
+    file_path: &Path,
+) -> Result<Option<Resolved<T>>>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    if let Some(value) = flag {
+        return Ok(Some(Resolved { value, source: Source::Flag(flag_name) }));
+    }
+    if let Some(value) = env::var(env_var).ok().filter(|v| !v.is_empty()) {
+        let value = value.parse().map_err(|e| anyhow::anyhow!("Invalid {env_var}: {e}"))?;
+        return Ok(Some(Resolved { value, source: Source::Env(env_var) }));
+    }
+    Ok(file_value.map(|value| Resolved { value, source: Source::ConfigFile(file_path.to_path_buf()) }))
+}
+
+/// ACNH saves of all installed emulators, or only of `emulator` and `profile` if given.
+/// Unreadable metadata is reported and treated as no saves found.
+fn detect_acnh_saves(emulator: Option<Emulator>, profile: Option<&str>) -> Vec<GameSave> {
+    let emulators = match emulator {
+        Some(emulator) => vec![emulator],
+        None => Emulator::installed(),
+    };
+
+    let mut saves = Vec::new();
+    for emulator in emulators {
+        match emulator.find_saves() {
+            Ok(found) => saves.extend(
+                found
+                    .into_iter()
+                    .filter(GameSave::is_acnh)
+                    .filter(|save| profile.is_none_or(|profile| save.matches_profile(profile))),
+            ),
+            Err(e) => eprintln!("Warning: failed to detect the ACNH save folder of {emulator}: {e:#}"),
+        }
+    }
+    saves
+}
+
+/// `$XDG_CONFIG_HOME/acnh-backup/config.toml`, or the platform equivalent
+pub fn default_config_path() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("acnh-backup")
+        .join("config.toml")
+}
+
+/// Save folder used when no emulator has an ACNH save
+pub fn default_save_dir() -> PathBuf {
+    ryujinx_save_dir().join("0000000000000001")
+}
+
+pub fn default_backup_dir() -> PathBuf {
+    ryujinx_save_dir().join("Backups")
+}
+
+fn ryujinx_save_dir() -> PathBuf {
+    let ryujinx_dir = Emulator::Ryujinx.data_dir().unwrap_or_else(|| PathBuf::from("Ryujinx"));
+    ryujinx::user_save_dir(&ryujinx_dir)
+}
@@ -0,0 +1,83 @@
+//! File level comparison of two backups, or of a backup and the current save.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::manifest::ManifestFile;
+
+/// A file that differs between two versions of a save.
+#[derive(Debug, Clone)]
+pub enum Change {
+    Added(ManifestFile),
+    Removed(ManifestFile),
+    Modified { old: ManifestFile, new: ManifestFile },
+}
+
+impl Change {
+    pub fn path(&self) -> &str {
+        match self {
+            Change::Added(file) | Change::Removed(file) => &file.path,
+            Change::Modified { new, .. } => &new.path,
+        }
+    }
+}
+
+/// Differences between two file lists, see [`diff_files`].
+#[derive(Debug, Default)]
+pub struct SaveDiff {
+    /// Changed files, sorted by path
+    pub changes: Vec<Change>,
+    pub unchanged: usize,
+}
+
+/// Compares the files of an old and a new version of a save by path, size and SHA-256.
+pub fn diff_files(old: &[ManifestFile], new: &[ManifestFile]) -> SaveDiff {
+    let mut old: BTreeMap<&str, &ManifestFile> = old.iter().map(|file| (file.path.as_str(), file)).collect();
+    let mut diff = SaveDiff::default();
+    for file in new {
+        match old.remove(file.path.as_str()) {
+            None => diff.changes.push(Change::Added(file.clone())),
+            Some(old_file) if old_file == file => diff.unchanged += 1,
+            Some(old_file) => diff.changes.push(Change::Modified { old: old_file.clone(), new: file.clone() }),
+        }
+    }
+    diff.changes.extend(old.into_values().map(|file| Change::Removed(file.clone())));
+    diff.changes.sort_by(|a, b| a.path().cmp(b.path()));
+    diff
+}
+
+/// First 12 characters of a hash, or all of it if a manifest holds a shorter one
+fn short_hash(hash: &str) -> &str {
+    hash.get(..12).unwrap_or(hash)
+}
+
+impl fmt::Display for SaveDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (mut added, mut removed, mut modified) = (0, 0, 0);
+        for change in &self.changes {
+            match change {
+                Change::Added(file) => {
+                    added += 1;
+                    writeln!(f, "  added     {}  ({} bytes)", file.path, file.size)?;
+                }
+                Change::Removed(file) => {
+                    removed += 1;
+                    writeln!(f, "  removed   {}  ({} bytes)", file.path, file.size)?;
+                }
+                Change::Modified { old, new } => {
+                    modified += 1;
+                    writeln!(
+                        f,
+                        "  modified  {}  {} -> {} bytes, sha256 {} -> {}",
+                        new.path,
+                        old.size,
+                        new.size,
+                        short_hash(&old.sha256),
+                        short_hash(&new.sha256)
+                    )?;
+                }
+            }
+        }
+        write!(f, "{added} added, {removed} removed, {modified} modified, {} unchanged", self.unchanged)
+    }
+}
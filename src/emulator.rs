@@ -0,0 +1,308 @@
+//! Emulator profiles: where each supported emulator keeps its data and how its save folders are laid out.
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::{ryujinx, yuzu};
+
+/// Title id of Animal Crossing: New Horizons
+pub const ACNH_TITLE_ID: u64 = 0x01006F8002326000;
+
+/// Supported emulators. Ryubing continues Ryujinx and uses the same data folder and layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Emulator {
+    #[serde(alias = "ryubing")]
+    #[value(alias = "ryubing")]
+    Ryujinx,
+    Yuzu,
+    Suyu,
+    Sudachi,
+}
+
+/// How the game files are stored inside a save folder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// `<save>/0` holds the committed files, `<save>/1` the working copy, next to `ExtraData0/1`
+    Ryujinx,
+    /// The game files are stored directly in the save folder
+    Yuzu,
+}
+
+/// A game save found in an emulator's data folder.
+#[derive(Debug, Clone)]
+pub struct GameSave {
+    pub emulator: Emulator,
+    pub title_id: u64,
+    /// Switch user profile UUID, zero for saves not tied to a user
+    pub user_id: u128,
+    /// Name of the user profile, if the emulator has a profile with this user id
+    pub profile_name: Option<String>,
+    pub path: PathBuf,
+}
+
+impl GameSave {
+    pub fn is_acnh(&self) -> bool {
+        self.title_id == ACNH_TITLE_ID && self.user_id != 0
+    }
+
+    /// Profile name and user id, for showing which profile a save belongs to
+    pub fn profile_label(&self) -> String {
+        let user_id = self.emulator.format_user_id(self.user_id);
+        match &self.profile_name {
+            Some(name) => format!("{name} ({user_id})"),
+            None => user_id,
+        }
+    }
+
+    /// Whether `selector` is the profile name (ignoring case) or user id of this save
+    pub fn matches_profile(&self, selector: &str) -> bool {
+        self.profile_name.as_deref().is_some_and(|name| name.eq_ignore_ascii_case(selector))
+            || u128::from_str_radix(selector, 16).is_ok_and(|user_id| user_id == self.user_id)
+    }
+
+    pub fn location(&self) -> SaveLocation {
+        SaveLocation { dir: self.path.clone(), emulator: self.emulator, user_id: Some(self.user_id) }
+    }
+}
+
+/// A Switch user profile of an emulator.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub user_id: u128,
+    pub name: String,
+}
+
+/// A save folder together with the emulator and user profile it belongs to.
+#[derive(Debug, Clone)]
+pub struct SaveLocation {
+    pub dir: PathBuf,
+    pub emulator: Emulator,
+    /// Switch user profile UUID, `None` if unknown
+    pub user_id: Option<u128>,
+}
+
+impl Emulator {
+    pub const ALL: [Emulator; 4] = [Emulator::Ryujinx, Emulator::Yuzu, Emulator::Suyu, Emulator::Sudachi];
+
+    /// Name used on the command line, in the config file and in backups
+    pub fn name(self) -> &'static str {
+        match self {
+            Emulator::Ryujinx => "ryujinx",
+            Emulator::Yuzu => "yuzu",
+            Emulator::Suyu => "suyu",
+            Emulator::Sudachi => "sudachi",
+        }
+    }
+
+    pub fn layout(self) -> Layout {
+        match self {
+            Emulator::Ryujinx => Layout::Ryujinx,
+            Emulator::Yuzu | Emulator::Suyu | Emulator::Sudachi => Layout::Yuzu,
+        }
+    }
+
+    /// Data folder of a regular (non-portable) installation
+    pub fn data_dir(self) -> Option<PathBuf> {
+        match self {
+            Emulator::Ryujinx => dirs::config_dir().map(|dir| dir.join("Ryujinx")),
+            Emulator::Yuzu => dirs::data_dir().map(|dir| dir.join("yuzu")),
+            Emulator::Suyu => dirs::data_dir().map(|dir| dir.join("suyu")),
+            Emulator::Sudachi => dirs::data_dir().map(|dir| dir.join("sudachi")),
+        }
+    }
+
+    pub fn is_installed(self) -> bool {
+        self.data_dir().is_some_and(|dir| dir.is_dir())
+    }
+
+    /// Emulators whose data folder exists
+    pub fn installed() -> Vec<Emulator> {
+        Emulator::ALL.into_iter().filter(|emulator| emulator.is_installed()).collect()
+    }
+
+    /// Formats a user id the way the emulator names it on disk
+    pub fn format_user_id(self, user_id: u128) -> String {
+        match self.layout() {
+            Layout::Ryujinx => ryujinx::format_user_id(user_id),
+            Layout::Yuzu => format!("{user_id:032X}"),
+        }
+    }
+
+    /// Guesses the emulator owning a save folder from its path
+    pub fn guess_from_path(path: &Path) -> Emulator {
+        let path = path.to_string_lossy().to_lowercase();
+        for emulator in [Emulator::Sudachi, Emulator::Suyu, Emulator::Yuzu] {
+            if path.contains(emulator.name()) {
+                return emulator;
+            }
+        }
+        if path.contains("nand") {
+            Emulator::Yuzu
+        } else {
+            Emulator::Ryujinx
+        }
+    }
+
+    /// Finds all game saves of this emulator, empty if it is not installed.
+    pub fn find_saves(self) -> Result<Vec<GameSave>> {
+        let Some(data_dir) = self.data_dir().filter(|dir| dir.is_dir()) else {
+            return Ok(Vec::new());
+        };
+
+        let saves: Vec<(u64, u128, PathBuf)> = match self.layout() {
+            Layout::Ryujinx => ryujinx::find_saves(&data_dir)?
+                .into_iter()
+                .map(|save| (save.title_id, save.user_id, save.path))
+                .collect(),
+            Layout::Yuzu => yuzu::find_saves(&data_dir)?
+                .into_iter()
+                .map(|save| (save.title_id, save.user_id, save.path))
+                .collect(),
+        };
+
+        // Profile names are only used for display, a broken profile list should not hide the saves
+        let profiles = self.profiles().unwrap_or_else(|e| {
+            eprintln!("Warning: failed to read the {self} user profiles: {e:#}");
+            Vec::new()
+        });
+
+        Ok(saves
+            .into_iter()
+            .map(|(title_id, user_id, path)| GameSave {
+                emulator: self,
+                title_id,
+                user_id,
+                profile_name: profiles.iter().find(|p| p.user_id == user_id).map(|p| p.name.clone()),
+                path,
+            })
+            .collect())
+    }
+
+    /// Reads the Switch user profiles, empty if the emulator is not installed.
+    pub fn profiles(self) -> Result<Vec<Profile>> {
+        let Some(data_dir) = self.data_dir().filter(|dir| dir.is_dir()) else {
+            return Ok(Vec::new());
+        };
+
+        match self.layout() {
+            Layout::Ryujinx => ryujinx::read_profiles(&data_dir),
+            Layout::Yuzu => yuzu::read_profiles(&data_dir),
+        }
+    }
+
+    /// The user a save folder belongs to, if it can be told from the folder
+    pub fn read_save_user(self, save_dir: &Path) -> Option<u128> {
+        match self.layout() {
+            Layout::Ryujinx => ryujinx::read_save_user(save_dir),
+            Layout::Yuzu => yuzu::read_save_user(save_dir),
+        }
+    }
+}
+
+impl fmt::Display for Emulator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Emulator {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "ryujinx" | "ryubing" => Ok(Emulator::Ryujinx),
+            "yuzu" => Ok(Emulator::Yuzu),
+            "suyu" => Ok(Emulator::Suyu),
+            "sudachi" => Ok(Emulator::Sudachi),
+            other => bail!("Unknown emulator {other}"),
+        }
+    }
+}
+
+impl Layout {
+    /// Detects the layout of an extracted save folder
+    pub fn detect(dir: &Path) -> Layout {
+        if dir.join("0").is_dir() || dir.join("ExtraData0").is_file() {
+            Layout::Ryujinx
+        } else {
+            Layout::Yuzu
+        }
+    }
+
+    /// Folder of `save_dir` holding the game files, like `main.dat`
+    pub fn files_dir(self, save_dir: &Path) -> PathBuf {
+        match self {
+            Layout::Ryujinx => save_dir.join("0"),
+            Layout::Yuzu => save_dir.to_path_buf(),
+        }
+    }
+
+    /// Folders of `save_dir` holding a copy of the game files: for Ryujinx the committed files and the
+    /// working copy
+    pub fn copies(self, save_dir: &Path) -> Vec<PathBuf> {
+        match self {
+            Layout::Ryujinx => vec![save_dir.join("0"), save_dir.join("1")],
+            Layout::Yuzu => vec![save_dir.to_path_buf()],
+        }
+    }
+
+    /// Rearranges the extracted save in `staging_dir` from layout `self` to layout `to`.
+    ///
+    /// For the Ryujinx layout the `ExtraData` files of the save folder the backup is restored into
+    /// are kept, as they describe the save slot (title and user) rather than the save content.
+    /// They are copied over from `current_dir` if it has them.
+    pub fn convert(self, to: Layout, staging_dir: &Path, current_dir: &Path) -> Result<()> {
+        let tmp_dir = staging_dir.with_extension("convert");
+        if tmp_dir.exists() {
+            fs::remove_dir_all(&tmp_dir)?;
+        }
+
+        match to {
+            _ if self == to => {}
+            Layout::Yuzu => {
+                // Keep only the committed files
+                let committed = staging_dir.join("0");
+                if !committed.is_dir() {
+                    bail!("Backup has no committed save data (folder 0)");
+                }
+                fs::rename(&committed, &tmp_dir)?;
+                fs::remove_dir_all(staging_dir)?;
+                fs::rename(&tmp_dir, staging_dir)?;
+            }
+            Layout::Ryujinx => {
+                fs::create_dir_all(&tmp_dir)?;
+                fs::rename(staging_dir, tmp_dir.join("0"))?;
+                copy_dir(&tmp_dir.join("0"), &tmp_dir.join("1"))?;
+                fs::rename(&tmp_dir, staging_dir)?;
+            }
+        }
+
+        if to == Layout::Ryujinx {
+            for name in ["ExtraData0", "ExtraData1"] {
+                let extra_data = current_dir.join(name);
+                if extra_data.is_file() {
+                    fs::copy(&extra_data, staging_dir.join(name))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    for entry in walkdir::WalkDir::new(from) {
+        let entry = entry?;
+        let target = to.join(entry.path().strip_prefix(from)?);
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
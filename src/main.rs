@@ -1,22 +1,334 @@
-use chrono::{Local, NaiveDateTime, TimeZone};
+mod archive;
+mod backups;
+mod cli;
+mod config;
+mod diff;
+mod emulator;
+mod manifest;
+mod pins;
+mod retention;
+mod ryujinx;
+mod running;
+mod savedata;
+mod schedule;
+mod store;
+mod tarball;
+mod watch;
+mod yuzu;
+
+use chrono::Local;
+use clap::Parser;
 use crossterm::{
     event::{self, Event, KeyCode},
     execute,
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    terminal::{disable_raw_mode, LeaveAlternateScreen},
 };
-use anyhow::{Context, Result};
-use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select};
-use std::{borrow::Cow, fs::{self, File}, iter::Zip};
+use anyhow::{bail, Context, Result};
+use dialoguer::{theme::ColorfulTheme, Confirm, Input, MultiSelect, Password, Select};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
 use std::io;
 use std::path::{Path, PathBuf};
 use std::process;
-use std::env;
-use zip::{write::FileOptions, ZipWriter};
-use zip::result::ZipError;
-use zip_extensions::{write::ZipWriterExtensions, zip_create_from_directory};
-use regex::Regex;
+use std::time::Duration;
+
+use cli::{Cli, Command, ConfigCommand};
+use config::Config;
+use archive::{
+    backup_files, create_backup_file, entry_name, extract_backup, list_entries, hash_save_files, is_encrypted, read_manifest, verify_backup, verify_extracted,
+    BackupFormat, BackupOptions,
+};
+use emulator::{Emulator, Layout, SaveLocation};
+use manifest::{fingerprint, Manifest, MANIFEST_VERSION};
+use backups::{find_backup, find_backup_of, list_backups, BackupEntry, PRE_RESTORE_NAME};
+use pins::Pins;
+use retention::{ByteSize, Island, RetentionConfig};
 
 fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let config = Config::load(&cli)?;
+
+    match cli.command {
+        Some(command) => run_command(command, &config),
+        None => run_interactive(&config),
+    }
+}
+
+/// Runs a single subcommand without any prompts.
+/// Errors are returned to `main`, which turns them into a non-zero exit code.
+fn run_command(command: Command, config: &Config) -> Result<()> {
+    match command {
+        Command::Backup { name, allow_duplicate } => {
+            let save = config.save()?;
+            if !allow_duplicate {
+                if let Some(latest) = unchanged_since_last_backup(&save, config.backup_dir())? {
+                    println!("Save unchanged since {}, no backup created", latest.filename);
+                    return Ok(());
+                }
+            }
+            let options = backup_options(config)?;
+            check_emulator(config, &save, "backing up")?;
+            let backup_path = create_backup(&save, config.backup_dir(), &name, &options)?;
+            println!("Backup created: {}", backup_path.display());
+            auto_prune(config);
+        }
+        Command::Watch { name, quiet } => {
+            let save = config.save()?;
+            let options = backup_options(config)?;
+            watch::watch(&save, Duration::from_secs(quiet), || auto_backup(config, &save, &name, &options))?;
+        }
+        Command::Schedule { every, cron, name, quiet } => {
+            let timer = match (every, cron) {
+                (Some(interval), _) => schedule::Timer::Every(interval),
+                (None, Some(cron)) => schedule::Timer::Cron(cron),
+                (None, None) => bail!("Set either --every or --cron"),
+            };
+            let save = config.save()?;
+            let options = backup_options(config)?;
+            schedule::schedule(&save, &timer, Duration::from_secs(quiet), || auto_backup(config, &save, &name, &options))?;
+        }
+        Command::Restore { backup, force, only, any_user } if !only.is_empty() => {
+            let save = config.save()?;
+            let backup = find_backup_of(config.backup_dir(), &backup, &save)?;
+            check_backup_user(&backup, &save, any_user)?;
+            let passphrase = passphrase_for(config, &backup.path)?;
+            let options = snapshot_options(config, passphrase.as_deref())?;
+            println!("Restoring {} from: {}", only.join(", "), backup.path.display());
+            check_emulator(config, &save, "restoring")?;
+            restore_files(&backup.path, passphrase.as_deref(), &save, config.backup_dir(), &options, force, |files| {
+                if let Some(missing) = only.iter().find(|name| !files.contains(name)) {
+                    bail!("The backup has no game file {missing}, it has: {}", files.join(", "));
+                }
+                Ok(only.clone())
+            })?;
+            println!("Restore complete.");
+        }
+        Command::Restore { backup, force, any_user, .. } => {
+            let save = config.save()?;
+            let backup = find_backup_of(config.backup_dir(), &backup, &save)?;
+            check_backup_user(&backup, &save, any_user)?;
+            let passphrase = passphrase_for(config, &backup.path)?;
+            let options = snapshot_options(config, passphrase.as_deref())?;
+            println!("Restoring directory from: {}", backup.path.display());
+            check_emulator(config, &save, "restoring")?;
+            restore_backup(&backup.path, passphrase.as_deref(), &save, config.backup_dir(), &options, force)?;
+            println!("Restore complete.");
+        }
+        Command::Undo => {
+            let save = config.save()?;
+            check_emulator(config, &save, "undoing the restore")?;
+            undo_last_restore(config, &save)?;
+            println!("Restore undone.");
+        }
+        Command::List => {
+            let backups = list_backups(config.backup_dir())?;
+            if backups.is_empty() {
+                println!("No backups found in the backup directory.");
+            }
+            for (i, backup) in backups.iter().enumerate() {
+                let emulator = backup.manifest.as_ref().map_or("", |m| m.emulator.name());
+                println!("{:>3}  {:<40}  {:<8}  {}", i + 1, backup.display_name(), emulator, backup.filename);
+            }
+        }
+        Command::Verify { backup } => {
+            let backups = match backup {
+                Some(backup) => vec![find_backup(config.backup_dir(), &backup)?],
+                None => list_backups(config.backup_dir())?,
+            };
+            let mut broken = 0;
+            // Asked for once, encrypted backups are expected to share the passphrase
+            let mut passphrase = None;
+            for backup in &backups {
+                let result = is_encrypted(&backup.path).and_then(|encrypted| {
+                    if encrypted && passphrase.is_none() {
+                        passphrase = Some(read_passphrase(config, false)?);
+                    }
+                    verify_backup(&backup.path, passphrase.as_deref().filter(|_| encrypted))
+                });
+                match result {
+                    Ok(report) => {
+                        if !report.is_ok() {
+                            broken += 1;
+                        }
+                        println!("{}: {}", backup.filename, report);
+                    }
+                    Err(e) => {
+                        broken += 1;
+                        println!("{}: unreadable: {e:#}", backup.filename);
+                    }
+                }
+            }
+            if broken > 0 {
+                bail!("{} of {} backups failed verification", broken, backups.len());
+            }
+        }
+        Command::Diff { from, to } => {
+            let from = find_backup(config.backup_dir(), &from)?;
+            let old_files = backup_files(&from.path, passphrase_for(config, &from.path)?.as_deref())?;
+            let (to_label, new_files) = match to {
+                Some(to) => {
+                    let to = find_backup(config.backup_dir(), &to)?;
+                    let files = backup_files(&to.path, passphrase_for(config, &to.path)?.as_deref())?;
+                    (to.filename, files)
+                }
+                None => {
+                    let save = config.save()?;
+                    let files = hash_save_files(&save.dir).context("Failed to read the save directory")?;
+                    (format!("current save {}", save.dir.display()), files)
+                }
+            };
+            println!("{} -> {}", from.filename, to_label);
+            println!("{}", diff::diff_files(&old_files, &new_files));
+        }
+        Command::Show { backup } => {
+            let backup = find_backup(config.backup_dir(), &backup)?;
+            println!("{}", backup.display_name());
+            println!("file:      {} ({})", backup.filename, ByteSize(backup.size));
+            if let Some(manifest) = &backup.manifest {
+                let user = manifest.user_id().map(|id| manifest.emulator.format_user_id(id));
+                println!("emulator:  {}{}", manifest.emulator, user.map(|id| format!(", user {id}")).unwrap_or_default());
+                println!("source:    {}", manifest.source_dir.display());
+            }
+            if is_encrypted(&backup.path)? {
+                println!("encrypted: yes");
+            }
+            if let Some(pin) = &backup.pin {
+                println!("pinned:    {}", pin.note.as_deref().unwrap_or("yes"));
+            }
+            println!();
+            for entry in list_entries(&backup.path)? {
+                let modified = entry.modified.map_or("-".to_string(), |time| time.format("%Y-%m-%d %H:%M:%S").to_string());
+                let size = if entry.is_dir { "dir".to_string() } else { entry.size.to_string() };
+                println!("  {modified:<19}  {size:>10}  {}", entry.name);
+            }
+        }
+        Command::Extract { backup, to, force } => {
+            let backup = find_backup(config.backup_dir(), &backup)?;
+            if to.exists() && fs::read_dir(&to)?.next().is_some() {
+                bail!("{} is not empty, extract into an empty or new directory", to.display());
+            }
+            let passphrase = passphrase_for(config, &backup.path)?;
+            fs::create_dir_all(&to).with_context(|| format!("Failed to create {}", to.display()))?;
+            extract_backup(&backup.path, &to, force, passphrase.as_deref()).context("Failed to extract backup")?;
+            println!("Extracted {} to {}", backup.filename, to.display());
+        }
+        Command::Inspect { backup } => {
+            let files = match backup {
+                Some(backup) => {
+                    let backup = find_backup(config.backup_dir(), &backup)?;
+                    with_extracted(config, &backup.path, savedata::decrypt_save)?
+                }
+                None => {
+                    let save = config.save()?;
+                    savedata::decrypt_save(&save.emulator.layout().files_dir(&save.dir))?
+                }
+            };
+            for file in &files {
+                println!("{:<24} {:>9} bytes  revision {}  {}", file.name, file.data.len(), file.revision, file.checksums());
+            }
+        }
+        Command::Delete { backup } => {
+            let backup = find_backup(config.backup_dir(), &backup)?;
+            if backup.pin.is_some() {
+                bail!("{} is pinned, unpin it before deleting it", backup.filename);
+            }
+            fs::remove_file(&backup.path)
+                .with_context(|| format!("Failed to delete {}", backup.path.display()))?;
+            println!("Deleted: {}", backup.filename);
+            if BackupFormat::of_path(&backup.path) == Some(BackupFormat::Store) {
+                remove_unused_chunks(config.backup_dir());
+            }
+        }
+        Command::Pin { backup, note } => {
+            let backup = find_backup(config.backup_dir(), &backup)?;
+            let mut pins = Pins::load(config.backup_dir())?;
+            pins.pin(&backup.filename, note);
+            pins.save(config.backup_dir())?;
+            println!("Pinned: {}", backup.filename);
+        }
+        Command::Unpin { backup } => {
+            let backup = find_backup(config.backup_dir(), &backup)?;
+            let mut pins = Pins::load(config.backup_dir())?;
+            if !pins.unpin(&backup.filename) {
+                bail!("{} is not pinned", backup.filename);
+            }
+            pins.save(config.backup_dir())?;
+            println!("Unpinned: {}", backup.filename);
+        }
+        Command::Prune { dry_run } => {
+            if config.retention.is_empty() {
+                bail!("No retention policy configured, add a [retention] table to {}", config.path.display());
+            }
+            prune_backups(config.backup_dir(), &config.retention, dry_run)?;
+        }
+        Command::Gc { dry_run } => {
+            let report = store::collect_garbage(config.backup_dir(), dry_run)?;
+            let verb = if dry_run { "Would remove" } else { "Removed" };
+            println!(
+                "{verb} {} unused chunks ({}), {} chunks in use",
+                report.removed,
+                ByteSize(report.freed),
+                report.kept
+            );
+        }
+        Command::Saves => {
+            let emulators = Emulator::installed();
+            if emulators.is_empty() {
+                println!("No supported emulator installed.");
+            }
+            for emulator in emulators {
+                let saves = emulator.find_saves()?;
+                println!("{} ({} saves)", emulator, saves.len());
+                for save in saves {
+                    println!(
+                        "  title {:016X}  user {}  {}{}",
+                        save.title_id,
+                        save.profile_label(),
+                        save.path.display(),
+                        if save.is_acnh() { "  ACNH" } else { "" }
+                    );
+                }
+            }
+        }
+        Command::Config { command: ConfigCommand::Show } => {
+            let status = if config.file_loaded { "loaded" } else { "not found" };
+            println!("config file: {} ({})", config.path.display(), status);
+            println!("emulator:    {} ({})", config.emulator.value, config.emulator.source);
+            if let Some(profile) = &config.profile {
+                println!("profile:     {} ({})", profile.value, profile.source);
+            }
+            match &config.save_dir {
+                Some(save_dir) => println!("save_dir:    {} ({})", save_dir.value.display(), save_dir.source),
+                None if config.detected_saves.is_empty() => println!("save_dir:    no ACNH save found"),
+                None => {
+                    println!("save_dir:    ambiguous, detected ACNH saves:");
+                    for save in &config.detected_saves {
+                        println!("               {:<8} {}  {}", save.emulator, save.profile_label(), save.path.display());
+                    }
+                }
+            }
+            println!("backup_dir:  {} ({})", config.backup_dir().display(), config.backup_dir.source);
+            println!("format:      {} ({})", config.format.value, config.format.source);
+            println!("compression: {} ({})", config.compression.value, config.compression.source);
+            if let Some(level) = &config.level {
+                let unused = if config.level().is_none() { ", not used by this format" } else { "" };
+                println!("level:       {} ({}{unused})", level.value, level.source);
+            }
+            println!("encrypt:     {} ({})", config.encrypt.value, config.encrypt.source);
+            if let Some(key_file) = &config.key_file {
+                println!("key_file:    {} ({})", key_file.value.display(), key_file.source);
+            }
+            println!("if_running:  {} ({})", config.if_running.value, config.if_running.source);
+            println!("retention:   {}", config.retention.default);
+            for (island, policy) in &config.retention.islands {
+                println!("               {island}: {policy}");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_interactive(config: &Config) -> Result<()> {
     // Enable raw mode for interactive terminal input
     //enable_raw_mode().expect("Failed to enable raw mode");
 
@@ -44,19 +356,18 @@ fn main() -> Result<()> {
             .with_prompt("What would you like to do?")
             .item("Backup")
             .item("Restore")
+            .item("Undo last restore")
             .item("Exit")
             .interact_opt()
             .expect("Failed to get user selection");
 
-        let selection = match selection {
-            Some(selection) => selection,
-            None => 2,
-        };
+        let selection = selection.unwrap_or(3);
 
-        match selection {
-            0 => backup_directory(),
-            1 => restore_directory(),
-            2 => {
+        let result = match selection {
+            0 => backup_directory(config),
+            1 => restore_directory(config),
+            2 => undo_directory(config),
+            3 => {
                 // Leave the alternate screen
                 execute!(std::io::stdout(), LeaveAlternateScreen).expect("Failed to leave alternate screen");
 
@@ -67,194 +378,664 @@ fn main() -> Result<()> {
                 process::exit(0);
             }
             _ => unreachable!(),
+        };
+
+        if let Err(e) = result {
+            println!("Error: {e:#}");
         }
     }
 }
 
-fn backup_directory() {
-    let source_dir = get_source_dir();
-    let target_dir = get_target_dir();
-
-    if !target_dir.exists() {
+fn backup_directory(config: &Config) -> Result<()> {
+    let save = select_save(config)?;
 
-        fs::create_dir_all(&target_dir).expect("Failed to create target directory");
+    if let Some(latest) = unchanged_since_last_backup(&save, config.backup_dir())? {
+        let create_anyway = Confirm::with_theme(&ColorfulTheme::default())
+            .default(false)
+            .with_prompt(format!("The save is unchanged since {}. Create another backup anyway?", latest.display_name()))
+            .interact()?;
+        if !create_anyway {
+            return Ok(());
+        }
     }
+
     // Use dialoguer to prompt for a custom name
     let custom_name: String = Input::with_theme(&ColorfulTheme::default())
         .default("Backup".to_string())
         .with_prompt("Enter a name for the backup")
-        .interact_text()
-        .expect("Failed to read input");
-
-    // Construct the backup name with the custom name and current datetime
-    let backup_name = format!(
-        "0000000000000001_{}_{}.zip",
-        custom_name,
-        chrono::Local::now().format("%Y-%m-%d_%H-%M-%S")
-    );
-
-    let backup_path = target_dir.join(backup_name);
+        .interact_text()?;
 
-    println!("Backing up directory to: {}", backup_path.display());
-    create_zip_backup(&source_dir, &backup_path).expect("Failed to create backup");
-    println!("Backup complete.");
+    let options = backup_options(config)?;
+    check_emulator(config, &save, "backing up")?;
+    let backup_path = create_backup(&save, config.backup_dir(), &custom_name, &options)?;
+    println!("Backup complete: {}", backup_path.display());
+    auto_prune(config);
 
-    // Prompt the user to continue
-    Confirm::with_theme(&ColorfulTheme::default())
-        .default(true)
-        .with_prompt("Press Enter to continue")
-        .interact_opt()
-        .expect("Failed to get user input");
+    wait_for_enter()
 }
 
-fn restore_directory() {
-    let target_dir = get_source_dir();
-    let backup_dir = get_target_dir();
-
-    // Define a regex pattern to match the backup file format
-    let re = Regex::new(r"^0000000000000001_(.+)_(\d{4}-\d{2}-\d{2})_(\d{2}-\d{2}-\d{2})\.zip$").unwrap();
+fn restore_directory(config: &Config) -> Result<()> {
+    let save = select_save(config)?;
+    let backup_dir = config.backup_dir();
 
     if !backup_dir.exists() {
         println!("Directory: {} does not exist", backup_dir.display());
-        return;
+        return Ok(());
     }
-    let mut backup_files: Vec<(String, String)> = fs::read_dir(&backup_dir)
-        .expect("Failed to read backup directory")
-        .filter_map(|entry| {
-            let entry = entry.ok()?;
-            if entry.path().is_file() && entry.path().extension().unwrap_or_default() == "zip" {
-                let filename = entry.path().file_name()?.to_string_lossy().to_string();
-                
-                if let Some(captures) = re.captures(&filename) {
-                    // Extract the custom name and datetime
-                    let custom_name = &captures[1];
-                    let date = &captures[2];
-                    let time = &captures[3];
-
-                    // Format the date and time into the desired output
-                    let datetime_str = format!("{} {}", date, time); // Seconds are always "00" as per the regex pattern
-                    let naive_datetime = NaiveDateTime::parse_from_str(&datetime_str, "%Y-%m-%d %H-%M-%S")
-                        .ok()
-                        .map(|dt| Local.from_local_datetime(&dt).unwrap())
-                        .unwrap_or_default();
-                    
-                    let formatted_date_time = naive_datetime.format("%Y-%m-%d %H:%M:%S").to_string();
-                    let display_name = format!("ACNH {} {}", custom_name, formatted_date_time);
-                    
-                    Some((display_name, filename))
-                } else {
-                    Some((filename.clone(), filename))
-                }
-            } else {
-                None
-            }
-        })
-        .collect();
-
-    backup_files.insert(0, ("Go back".to_string(), "Go back".to_string()));
 
-    if backup_files.is_empty() {
+    let backups = list_backups(backup_dir)?;
+    if backups.is_empty() {
         println!("No backups found in the backup directory.");
-        return;
+        return Ok(());
     }
 
+    let mut items = vec!["Go back".to_string()];
+    items.extend(backups.iter().map(BackupEntry::display_name));
+
     let selected_backup = Select::with_theme(&ColorfulTheme::default())
         .with_prompt("Select a backup to restore")
-        .items(&backup_files.iter().map(|pair| pair.0.as_str()).collect::<Vec<&str>>())
-        .interact_opt()
-        .expect("Failed to get user selection");
+        .items(&items)
+        .default(0)
+        .interact_opt()?;
 
-    let selected_backup = match selected_backup {
-        Some(selected_backup) => selected_backup,
-        None => return,
+    // Index 0 is "Go back"
+    let backup = match selected_backup {
+        Some(0) | None => return Ok(()),
+        Some(i) => &backups[i - 1],
     };
 
-    if &backup_files[selected_backup].1 == "Go back" {
-        return;
+    if let Some((from, _)) = other_user(backup, &save) {
+        let restore_anyway = Confirm::with_theme(&ColorfulTheme::default())
+            .default(false)
+            .with_prompt(format!(
+                "The backup was taken from user {}, not from this save's user. Restore it anyway?",
+                save.emulator.format_user_id(from)
+            ))
+            .interact()?;
+        if !restore_anyway {
+            return Ok(());
+        }
+    }
+
+    println!("Restoring directory from: {}", backup.path.display());
+    let passphrase = passphrase_for(config, &backup.path)?;
+    // Check the archive up front so the user can decide to restore a broken backup anyway
+    let report = verify_backup(&backup.path, passphrase.as_deref())?;
+    let force = if report.is_ok() {
+        false
+    } else {
+        println!("{report}");
+        let restore_anyway = Confirm::with_theme(&ColorfulTheme::default())
+            .default(false)
+            .with_prompt("The backup failed verification. Restore it anyway?")
+            .interact()?;
+        if !restore_anyway {
+            return Ok(());
+        }
+        true
+    };
+
+    let whole_save = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt("What should be restored?")
+        .items(&["The whole save", "Only selected files"])
+        .default(0)
+        .interact()?
+        == 0;
+
+    let options = snapshot_options(config, passphrase.as_deref())?;
+    check_emulator(config, &save, "restoring")?;
+    if whole_save {
+        restore_backup(&backup.path, passphrase.as_deref(), &save, backup_dir, &options, force)?;
+        println!("Restore complete.");
+    } else {
+        let restored = restore_files(&backup.path, passphrase.as_deref(), &save, backup_dir, &options, force, |files| {
+            let selected = MultiSelect::with_theme(&ColorfulTheme::default())
+                .with_prompt("Select the files to restore (space to select, enter to confirm)")
+                .items(files)
+                .interact()?;
+            Ok(selected.into_iter().map(|i| files[i].clone()).collect())
+        })?;
+        match restored {
+            0 => println!("Nothing selected, save directory left untouched."),
+            n => println!("Restored {n} files."),
+        }
     }
-    
-    // Convert the display name back to the original filename
-    let original_filename = &backup_files[selected_backup].1;
-    
-    let backup_path = backup_dir.join(original_filename);
-    
-    println!("Restoring directory from: {}", backup_path.display());
-    fs::remove_dir_all(&target_dir).expect("Failed to remove target directory");
-    fs::create_dir_all(&target_dir).expect("Failed to create target directory");
-    extract_zip_backup(&backup_path, &target_dir).expect("Failed to restore backup");
-    println!("Restore complete.");
 
+    wait_for_enter()
+}
+
+fn undo_directory(config: &Config) -> Result<()> {
+    let confirmed = Confirm::with_theme(&ColorfulTheme::default())
+        .default(false)
+        .with_prompt("Replace the current save with the snapshot taken before the last restore?")
+        .interact()?;
+    if !confirmed {
+        return Ok(());
+    }
+
+    let save = select_save(config)?;
+    check_emulator(config, &save, "undoing the restore")?;
+    undo_last_restore(config, &save)?;
+    println!("Restore undone.");
+
+    wait_for_enter()
+}
+
+/// Returns the configured save, letting the user pick one if several profiles or emulators have an ACNH save.
+fn select_save(config: &Config) -> Result<SaveLocation> {
+    if config.save_dir.is_some() || config.detected_saves.len() < 2 {
+        return config.save();
+    }
+
+    let items: Vec<String> = config
+        .detected_saves
+        .iter()
+        .map(|save| format!("{:<8} {}", save.emulator, save.profile_label()))
+        .collect();
+    let selection = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt("Which island?")
+        .items(&items)
+        .default(0)
+        .interact()?;
+    Ok(config.detected_saves[selection].location())
+}
+
+/// Handles a running emulator of `save` before `action` according to the `if_running` setting:
+/// warns, fails, or waits until it has exited.
+fn check_emulator(config: &Config, save: &SaveLocation, action: &str) -> Result<()> {
+    running::check_not_running(save.emulator, config.if_running.value, action)
+}
+
+fn wait_for_enter() -> Result<()> {
     // Prompt the user to continue
     Confirm::with_theme(&ColorfulTheme::default())
         .default(true)
         .with_prompt("Press Enter to continue")
-        .interact_opt()
-        .expect("Failed to get user input");
+        .interact_opt()?;
+    Ok(())
+}
+
+/// Creates a new backup of the save folder in `target_dir` and returns the path of the backup file.
+fn create_backup(save: &SaveLocation, target_dir: &Path, custom_name: &str, options: &BackupOptions) -> Result<PathBuf> {
+    check_backup_name(custom_name)?;
+    let source_dir = save.dir.as_path();
+    if !source_dir.exists() {
+        bail!("Save directory {} does not exist", source_dir.display());
+    }
+    if !target_dir.exists() {
+        fs::create_dir_all(target_dir).context("Failed to create target directory")?;
+    }
+
+    // Prefix the backup with the save folder id, keeping the old default for other folder names
+    let save_id = source_dir
+        .file_name()
+        .and_then(|name| name.to_str())
+        .filter(|name| name.len() == 16 && name.chars().all(|c| c.is_ascii_hexdigit()))
+        .unwrap_or("0000000000000001");
+
+    // Construct the backup name with the custom name and current datetime
+    let now = Local::now();
+    let stem = format!("{}_{}_{}", save_id, custom_name, now.format("%Y-%m-%d_%H-%M-%S"));
+    let backup_path = claim_backup_path(target_dir, &stem, options.format)?;
+
+    let manifest = Manifest {
+        format_version: MANIFEST_VERSION,
+        name: custom_name.to_string(),
+        created: now.fixed_offset(),
+        source_dir: source_dir.to_path_buf(),
+        emulator: save.emulator,
+        user_id: save.user_id.map(|user_id| format!("{user_id:032x}")),
+        tool_version: env!("CARGO_PKG_VERSION").to_string(),
+        files: Vec::new(),
+    };
+
+    println!("Backing up directory to: {}", backup_path.display());
+    if let Err(e) = create_backup_file(options, source_dir, &backup_path, manifest) {
+        // Do not leave a partial backup behind, the file was created by this call
+        let _ = fs::remove_file(&backup_path);
+        return Err(e.context("Failed to create backup"));
+    }
+    Ok(backup_path)
+}
+
+/// Makes sure a custom backup name can be used in a file name without leaving the backup directory.
+fn check_backup_name(name: &str) -> Result<()> {
+    if name.trim().is_empty() {
+        bail!("The backup name is empty");
+    }
+    if name.contains(['/', '\\']) || name.contains("..") {
+        bail!("Invalid backup name {name:?}, it must not contain `/`, `\\` or `..`");
+    }
+    Ok(())
 }
 
-fn get_source_dir() -> PathBuf {
-    let username = whoami::username();
-    match std::env::consts::OS {
-        "windows" => Path::new(&format!(r"C:\Users\{username}\AppData\Roaming\Ryujinx\bis\user\save\0000000000000001")).to_path_buf(),
-        "macos" => Path::new(&format!(r"/Users/{username}/Library/Application Support/Ryujinx/bis/user/save/0000000000000001")).to_path_buf(),
-        _ => Path::new(&format!(r"/home/{username}/.config/Ryujinx/bis/user/save/0000000000000001")).to_path_buf()
+/// Creates an empty file `<stem>.<extension>` in `target_dir` for a new backup and returns its path.
+///
+/// Backup names only go down to the second, so if a backup of that name exists, `-2`, `-3`, ... is
+/// added to the stem rather than overwriting it.
+fn claim_backup_path(target_dir: &Path, stem: &str, format: BackupFormat) -> Result<PathBuf> {
+    let mut number = 1;
+    loop {
+        let name = match number {
+            1 => format!("{stem}.{}", format.extension()),
+            n => format!("{stem}-{n}.{}", format.extension()),
+        };
+        let path = target_dir.join(name);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => return Ok(path),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => number += 1,
+            Err(e) => return Err(e).with_context(|| format!("Failed to create {}", path.display())),
+        }
     }
 }
 
-fn get_target_dir() -> PathBuf {
-    let username = whoami::username();
-    let os = match std::env::consts::OS {
-        "windows" => Path::new(&format!(r"C:\Users\{username}\AppData\Roaming\Ryujinx\bis\user\save\Backups")).to_path_buf(),
-        "macos" => Path::new(&format!(r"/Users/{username}/Library/Application Support/Ryujinx/bis/user/save/Backups")).to_path_buf(),
-        _ => {
-            let username = env::var("USER").expect("Failed to get user name").to_string();
-            Path::new(&format!(r"/home/{username}/.config/Ryujinx/bis/user/save/Backups")).to_path_buf()
-        },
+/// Returns the newest backup of the save folder if the folder still has the same content fingerprint.
+fn unchanged_since_last_backup(save: &SaveLocation, backup_dir: &Path) -> Result<Option<BackupEntry>> {
+    let Some(latest) = list_backups(backup_dir)?
+        .into_iter()
+        .find(|backup| backup.manifest.as_ref().is_some_and(|m| m.source_dir == save.dir))
+    else {
+        return Ok(None);
     };
-    os
+    let current = fingerprint(&hash_save_files(&save.dir).context("Failed to read the save directory")?);
+    Ok(Some(latest).filter(|backup| backup.manifest.as_ref().is_some_and(|m| m.fingerprint() == current)))
 }
 
-fn create_zip_backup(source_dir: &Path, backup_path: &Path) -> Result<()>{
+/// Creates a backup for watch and schedule mode, unless the save is unchanged since its last backup.
+/// Errors are only reported, so the long running modes keep going.
+fn auto_backup(config: &Config, save: &SaveLocation, name: &str, options: &BackupOptions) {
+    let result = unchanged_since_last_backup(save, config.backup_dir()).and_then(|latest| match latest {
+        Some(latest) => {
+            println!("Save unchanged since {}, skipping", latest.filename);
+            Ok(())
+        }
+        None => {
+            let backup_path = create_backup(save, config.backup_dir(), name, options)?;
+            println!("Backup created: {}", backup_path.display());
+            auto_prune(config);
+            Ok(())
+        }
+    });
+    if let Err(e) = result {
+        println!("Error: {e:#}");
+    }
+}
 
-    let file = File::create(backup_path)?;
-    let zip = ZipWriter::new(file);
-    zip.create_from_directory(&source_dir.into())?;
-    Ok(())
+/// Options for new backups. If backups are encrypted, the passphrase is read from the key file or
+/// asked for.
+fn backup_options(config: &Config) -> Result<BackupOptions> {
+    let passphrase = if config.encrypt.value { Some(read_passphrase(config, true)?) } else { None };
+    Ok(config.backup_options(passphrase))
 }
 
-fn extract_zip_backup(backup_path: &Path, target_dir: &Path) -> Result<(), ZipError> {
-    let file = std::fs::File::open(backup_path)?;
-    let mut zip = zip::ZipArchive::new(file)?;
+/// Options for the pre-restore snapshot. An encrypted snapshot reuses the passphrase of the restored
+/// backup if it has one, so it is not asked for twice.
+fn snapshot_options(config: &Config, passphrase: Option<&str>) -> Result<BackupOptions> {
+    match passphrase {
+        Some(passphrase) if config.encrypt.value => Ok(config.backup_options(Some(passphrase.to_string()))),
+        _ => backup_options(config),
+    }
+}
 
-    for i in 0..zip.len() {
-        let mut file = zip.by_index(i)?;
-        let outpath = if file.name().ends_with('/') {
-            target_dir.join(file.name())
-        } else {
-            target_dir.join(file.name())
-        };
+/// Passphrase needed to read the backup at `backup_path`, `None` if it is not encrypted.
+fn passphrase_for(config: &Config, backup_path: &Path) -> Result<Option<String>> {
+    if !is_encrypted(backup_path)? {
+        return Ok(None);
+    }
+    read_passphrase(config, false).map(Some)
+}
 
-        if (*file.name()).ends_with('/') {
-            fs::create_dir_all(&outpath)?;
-        } else {
-            if let Some(p) = outpath.parent() {
-                if !p.exists() {
-                    fs::create_dir_all(&p)?;
-                }
+/// Reads the backup passphrase from the configured key file, or asks for it.
+/// With `confirm` the passphrase has to be entered twice, for new backups.
+fn read_passphrase(config: &Config, confirm: bool) -> Result<String> {
+    if let Some(key_file) = &config.key_file {
+        let path = &key_file.value;
+        let content = fs::read_to_string(path).with_context(|| format!("Failed to read key file {}", path.display()))?;
+        let passphrase = content.trim_end_matches(['\r', '\n']);
+        if passphrase.is_empty() {
+            bail!("Key file {} is empty", path.display());
+        }
+        return Ok(passphrase.to_string());
+    }
+
+    let theme = ColorfulTheme::default();
+    let prompt = Password::with_theme(&theme).with_prompt("Backup passphrase");
+    let prompt = if confirm {
+        prompt.with_confirmation("Repeat the passphrase", "The passphrases do not match")
+    } else {
+        prompt
+    };
+    prompt
+        .interact()
+        .context("Failed to read the passphrase, set a key file with --key-file to run without a terminal")
+}
+
+/// Extracts a backup into a temporary folder and runs `f` on the folder holding its game files.
+/// The folder is removed afterwards.
+fn with_extracted<T>(config: &Config, backup_path: &Path, f: impl FnOnce(&Path) -> Result<T>) -> Result<T> {
+    let passphrase = passphrase_for(config, backup_path)?;
+    let tmp_dir = std::env::temp_dir().join(format!("acnh-backup-{}", process::id()));
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).context("Failed to remove leftover temporary directory")?;
+    }
+    let result = fs::create_dir_all(&tmp_dir)
+        .context("Failed to create temporary directory")
+        .and_then(|_| extract_backup(backup_path, &tmp_dir, false, passphrase.as_deref()))
+        .and_then(|_| f(&Layout::detect(&tmp_dir).files_dir(&tmp_dir)));
+    let _ = fs::remove_dir_all(&tmp_dir);
+    result
+}
+
+/// Replaces the contents of the save folder with the contents of the backup at `backup_path`.
+///
+/// The backup is extracted into a sibling staging directory and verified first, so a corrupt
+/// archive never touches the save folder. Backups taken from another emulator are converted to the
+/// layout of the target emulator. The current contents of the save folder are then saved as a
+/// pre-restore snapshot in `backup_dir` with `options`, so a wrong restore can be reverted with
+/// [`undo_last_restore`], and the staging directory is swapped in with renames.
+/// `passphrase` decrypts the backup if it is encrypted.
+fn restore_backup(
+    backup_path: &Path,
+    passphrase: Option<&str>,
+    save: &SaveLocation,
+    backup_dir: &Path,
+    options: &BackupOptions,
+    force: bool,
+) -> Result<()> {
+    let staging_dir = stage_backup(backup_path, passphrase, save, force)?;
+    if let Err(e) = save_pre_restore_snapshot(save, backup_dir, options) {
+        let _ = fs::remove_dir_all(&staging_dir);
+        return Err(e);
+    }
+    swap_in_dir(&staging_dir, &save.dir).context("Failed to restore backup")
+}
+
+/// Restores only some game files of a backup, leaving the rest of the save folder untouched.
+///
+/// The backup is staged like in [`restore_backup`], then `select` picks from its game files, e.g.
+/// `Villager1/personal.dat`. Header files are not offered, they are restored together with their
+/// save file. After the pre-restore snapshot is taken, the selected files replace the ones in every
+/// copy of the game files in the save folder. Returns the number of restored files, 0 if nothing was
+/// selected.
+fn restore_files(
+    backup_path: &Path,
+    passphrase: Option<&str>,
+    save: &SaveLocation,
+    backup_dir: &Path,
+    options: &BackupOptions,
+    force: bool,
+    select: impl FnOnce(&[String]) -> Result<Vec<String>>,
+) -> Result<usize> {
+    let staging_dir = stage_backup(backup_path, passphrase, save, force)?;
+    let result = copy_selected_files(&staging_dir, save, backup_dir, options, select);
+    let _ = fs::remove_dir_all(&staging_dir);
+    result
+}
+
+fn copy_selected_files(
+    staging_dir: &Path,
+    save: &SaveLocation,
+    backup_dir: &Path,
+    options: &BackupOptions,
+    select: impl FnOnce(&[String]) -> Result<Vec<String>>,
+) -> Result<usize> {
+    let layout = save.emulator.layout();
+    let files_dir = layout.files_dir(staging_dir);
+    let mut files = Vec::new();
+    for entry in walkdir::WalkDir::new(&files_dir).min_depth(1).sort_by_file_name() {
+        let entry = entry?;
+        let name = entry_name(entry.path().strip_prefix(&files_dir)?);
+        if entry.file_type().is_file() && !savedata::is_header(&name) {
+            files.push(name);
+        }
+    }
+
+    let selected = select(&files)?;
+    if selected.is_empty() {
+        return Ok(0);
+    }
+    save_pre_restore_snapshot(save, backup_dir, options)?;
+
+    for name in &selected {
+        let mut names = vec![PathBuf::from(name)];
+        let header = savedata::header_path(Path::new(name));
+        if files_dir.join(&header).is_file() {
+            names.push(header);
+        }
+        for copy_dir in layout.copies(&save.dir) {
+            for name in &names {
+                replace_file(&files_dir.join(name), &copy_dir.join(name)).with_context(|| {
+                    format!("Failed to restore {}, use `undo` to go back to the save before the restore", name.display())
+                })?;
             }
-            let mut outfile = fs::File::create(&outpath)?;
-            io::copy(&mut file, &mut outfile)?;
         }
+        println!("Restored {name}");
+    }
+    Ok(selected.len())
+}
+
+/// Replaces `target` with a copy of `source` through a temporary file, so it is never left half written.
+fn replace_file(source: &Path, target: &Path) -> Result<()> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let file_name = target.file_name().context("Invalid file name")?;
+    let tmp_path = target.with_file_name(format!("{}.restore-tmp", file_name.to_string_lossy()));
+    fs::copy(source, &tmp_path)?;
+    fs::rename(&tmp_path, target)?;
+    Ok(())
+}
+
+/// Extracts, verifies and converts a backup into a staging directory next to the save folder and
+/// returns its path. Nothing is left behind if this fails.
+fn stage_backup(backup_path: &Path, passphrase: Option<&str>, save: &SaveLocation, force: bool) -> Result<PathBuf> {
+    let target_dir = save.dir.as_path();
+    let staging_dir = sibling_dir(target_dir, "restore-staging")?;
+    if staging_dir.exists() {
+        fs::remove_dir_all(&staging_dir).context("Failed to remove leftover staging directory")?;
+    }
+
+    let staged = fs::create_dir_all(&staging_dir)
+        .context("Failed to create staging directory")
+        .and_then(|_| extract_backup(backup_path, &staging_dir, force, passphrase).context("Failed to extract backup"))
+        .and_then(|_| verify_extracted(backup_path, &staging_dir))
+        .and_then(|_| {
+            let manifest = read_manifest(backup_path)?;
+            let layout = match &manifest {
+                Some(manifest) => manifest.emulator.layout(),
+                None => Layout::detect(&staging_dir),
+            };
+            layout
+                .convert(save.emulator.layout(), &staging_dir, target_dir)
+                .with_context(|| format!("Failed to convert backup for {}", save.emulator))
+        });
+    if let Err(e) = staged {
+        let _ = fs::remove_dir_all(&staging_dir);
+        return Err(e.context("Failed to restore backup, save directory left untouched"));
+    }
+    Ok(staging_dir)
+}
+
+/// Saves the current contents of the save folder as a pre-restore snapshot, unless it is empty.
+fn save_pre_restore_snapshot(save: &SaveLocation, backup_dir: &Path, options: &BackupOptions) -> Result<()> {
+    if save.dir.exists() && fs::read_dir(&save.dir)?.next().is_some() {
+        let snapshot = create_backup(save, backup_dir, PRE_RESTORE_NAME, options)
+            .context("Failed to create pre-restore snapshot, save directory left untouched")?;
+        println!("Saved current save as: {}", snapshot.display());
+    }
+    Ok(())
+}
+
+/// Replaces `target_dir` with `staging_dir` using renames.
+/// If anything fails, the original `target_dir` is put back in place.
+fn swap_in_dir(staging_dir: &Path, target_dir: &Path) -> Result<()> {
+    let old_dir = sibling_dir(target_dir, "restore-old")?;
+    if old_dir.exists() {
+        fs::remove_dir_all(&old_dir).context("Failed to remove leftover directory")?;
+    }
+
+    let had_target = target_dir.exists();
+    if had_target {
+        if let Err(e) = fs::rename(target_dir, &old_dir) {
+            let _ = fs::remove_dir_all(staging_dir);
+            return Err(e).context("Failed to move save directory aside");
+        }
+    }
+
+    if let Err(e) = fs::rename(staging_dir, target_dir) {
+        // Roll back to the original save directory
+        if had_target {
+            fs::rename(&old_dir, target_dir).with_context(|| {
+                format!("Rollback failed, original save is in {}", old_dir.display())
+            })?;
+        }
+        let _ = fs::remove_dir_all(staging_dir);
+        return Err(e).context("Failed to move restored save into place, original save kept");
+    }
+
+    if had_target {
+        fs::remove_dir_all(&old_dir)
+            .with_context(|| format!("Restore complete, but failed to remove {}", old_dir.display()))?;
+    }
+    Ok(())
+}
+
+/// Returns `<dir>.<suffix>` next to `dir`, used for staging directories on the same filesystem.
+fn sibling_dir(dir: &Path, suffix: &str) -> Result<PathBuf> {
+    let name = dir
+        .file_name()
+        .with_context(|| format!("Invalid save directory {}", dir.display()))?;
+    Ok(dir.with_file_name(format!("{}.{suffix}", name.to_string_lossy())))
+}
 
-        // Get and Set permissions
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            if let Some(mode) = file.unix_mode() {
-                fs::set_permissions(&outpath, fs::Permissions::from_mode(mode))?;
+/// The user a backup was taken from and the user of `save`, if both are known and differ
+fn other_user(backup: &BackupEntry, save: &SaveLocation) -> Option<(u128, u128)> {
+    let from = backup.manifest.as_ref().and_then(Manifest::user_id)?;
+    save.user_id.filter(|&to| to != from).map(|to| (from, to))
+}
+
+/// Refuses to restore a backup of another user into `save`, unless `any_user` is set.
+fn check_backup_user(backup: &BackupEntry, save: &SaveLocation, any_user: bool) -> Result<()> {
+    match other_user(backup, save) {
+        Some((from, to)) if !any_user => bail!(
+            "{} was taken from user {}, not from user {} of the save, use --any-user to restore it anyway",
+            backup.filename,
+            save.emulator.format_user_id(from),
+            save.emulator.format_user_id(to)
+        ),
+        Some((from, to)) => {
+            println!(
+                "Note: backup was taken from user {}, restoring into user {}",
+                save.emulator.format_user_id(from),
+                save.emulator.format_user_id(to)
+            );
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+/// Restores the most recent pre-restore snapshot of `save`.
+///
+/// This takes a new snapshot of the current save first, so running it twice redoes the restore.
+fn undo_last_restore(config: &Config, save: &SaveLocation) -> Result<()> {
+    let backup_dir = config.backup_dir();
+    let snapshot = list_backups(backup_dir)?
+        .into_iter()
+        .find(|backup| backup.is_pre_restore() && backup.is_from(save))
+        .context("No pre-restore snapshot of this save found, nothing to undo")?;
+
+    let passphrase = passphrase_for(config, &snapshot.path)?;
+    let options = snapshot_options(config, passphrase.as_deref())?;
+    println!("Restoring directory from: {}", snapshot.path.display());
+    restore_backup(&snapshot.path, passphrase.as_deref(), save, backup_dir, &options, false)
+}
+
+/// Applies the retention policy of every island to the backups in `backup_dir`.
+/// With `dry_run` every backup is listed with whether it would be kept and why, and nothing is deleted.
+fn prune_backups(backup_dir: &Path, retention: &RetentionConfig, dry_run: bool) -> Result<()> {
+    let mut backups = list_backups(backup_dir)?;
+    // Count the chunks of snapshots as well, so the size limit covers the backup store
+    let mut snapshots: Vec<&mut BackupEntry> = backups
+        .iter_mut()
+        .filter(|backup| BackupFormat::of_path(&backup.path) == Some(BackupFormat::Store))
+        .collect();
+    let paths: Vec<&Path> = snapshots.iter().map(|backup| backup.path.as_path()).collect();
+    let sizes = store::snapshot_sizes(backup_dir, &paths);
+    for (backup, size) in snapshots.iter_mut().zip(sizes) {
+        backup.size = size;
+    }
+    // Profile names let the config file refer to islands by name
+    let profiles: Vec<_> = Emulator::installed()
+        .into_iter()
+        .flat_map(|emulator| emulator.profiles().unwrap_or_default())
+        .collect();
+
+    let mut islands: BTreeMap<Island, Vec<&BackupEntry>> = BTreeMap::new();
+    for backup in &backups {
+        let key = backup.island_key();
+        let profile_name = u128::from_str_radix(&key, 16)
+            .ok()
+            .filter(|_| key.len() == 32)
+            .and_then(|user_id| profiles.iter().find(|p| p.user_id == user_id))
+            .map(|p| p.name.clone());
+        islands.entry(Island { key, profile_name }).or_default().push(backup);
+    }
+
+    let now = Local::now();
+    let mut deleted = 0;
+    for (island, backups) in &islands {
+        let policy = retention.policy_for(island);
+        if dry_run {
+            println!("Island {island}: {policy}");
+        }
+        for (backup, decision) in backups.iter().zip(retention::plan(&policy, backups, now)) {
+            if decision.keep {
+                if dry_run {
+                    println!("  keep    {}  ({})", backup.filename, decision.reason);
+                }
+            } else if dry_run {
+                println!("  delete  {}  ({})", backup.filename, decision.reason);
+                deleted += 1;
+            } else {
+                fs::remove_file(&backup.path)
+                    .with_context(|| format!("Failed to delete {}", backup.path.display()))?;
+                println!("Deleted: {} ({})", backup.filename, decision.reason);
+                deleted += 1;
             }
         }
     }
 
+    if dry_run {
+        println!("{deleted} of {} backups would be deleted.", backups.len());
+    } else {
+        println!("Deleted {deleted} of {} backups.", backups.len());
+        if deleted > 0 {
+            remove_unused_chunks(backup_dir);
+        }
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Removes the store chunks left unused after deleting snapshots. The deletion already succeeded,
+/// so a failure is only reported and `gc` can be run later.
+fn remove_unused_chunks(backup_dir: &Path) {
+    match store::collect_garbage(backup_dir, false) {
+        Ok(report) if report.removed > 0 => {
+            println!("Removed {} unused chunks ({})", report.removed, ByteSize(report.freed));
+        }
+        Ok(_) => {}
+        Err(e) => println!("Warning: failed to remove unused chunks: {e:#}"),
+    }
+}
+
+/// Prunes old backups after a new one was created, if a retention policy is configured.
+/// The new backup is already saved, so a failure is only reported.
+fn auto_prune(config: &Config) {
+    if config.retention.is_empty() {
+        return;
+    }
+    if let Err(e) = prune_backups(config.backup_dir(), &config.retention, false) {
+        println!("Warning: failed to prune old backups: {e:#}");
+    }
+}
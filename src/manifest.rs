@@ -0,0 +1,67 @@
+use chrono::{DateTime, FixedOffset};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+
+use crate::emulator::Emulator;
+
+/// Name of the manifest entry at the root of every backup archive
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+/// Version of the manifest format, bumped on incompatible changes
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Metadata describing a backup, stored as `manifest.json` inside the archive.
+///
+/// Unlike the file name, the manifest survives renaming the archive.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Manifest {
+    pub format_version: u32,
+    /// Custom name given to the backup
+    pub name: String,
+    pub created: DateTime<FixedOffset>,
+    /// Save folder the backup was taken from
+    pub source_dir: PathBuf,
+    pub emulator: Emulator,
+    /// Switch user profile UUID as 32 hex digits, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    pub tool_version: String,
+    pub files: Vec<ManifestFile>,
+}
+
+/// A file of the save folder, with the path relative to the save folder using `/` separators.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestFile {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl Manifest {
+    /// Content fingerprint of the backed up save folder, see [`fingerprint`]
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.files)
+    }
+
+    pub fn user_id(&self) -> Option<u128> {
+        self.user_id
+            .as_deref()
+            .and_then(|user_id| u128::from_str_radix(user_id, 16).ok())
+    }
+}
+
+/// Content fingerprint of a save folder: the SHA-256 over the path, size and hash of every file.
+/// Two folders with the same fingerprint hold the same files with the same content.
+pub fn fingerprint(files: &[ManifestFile]) -> String {
+    let mut hasher = Sha256::new();
+    for file in files {
+        hasher.update(format!("{}\0{}\0{}\n", file.path, file.size, file.sha256));
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Hex encoded SHA-256 of `data`
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
@@ -0,0 +1,65 @@
+//! Pinned backups, which are never deleted by `prune` or `delete`.
+//!
+//! Pins are kept in a sidecar index in the backup directory rather than in the archives, so pinning
+//! does not rewrite a backup.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, FixedOffset, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the pin index in the backup directory
+pub const PINS_NAME: &str = "pinned.json";
+
+/// The pin index, mapping backup file names to their pin.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Pins {
+    #[serde(default)]
+    pub pinned: BTreeMap<String, Pin>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Pin {
+    pub pinned_at: DateTime<FixedOffset>,
+    /// Why the backup is kept, e.g. the event it captures
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+impl Pins {
+    fn path(backup_dir: &Path) -> PathBuf {
+        backup_dir.join(PINS_NAME)
+    }
+
+    /// Reads the pin index of `backup_dir`, empty if there is none yet.
+    pub fn load(backup_dir: &Path) -> Result<Pins> {
+        let path = Pins::path(backup_dir);
+        if !path.exists() {
+            return Ok(Pins::default());
+        }
+        let content = fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&content).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    pub fn save(&self, backup_dir: &Path) -> Result<()> {
+        let path = Pins::path(backup_dir);
+        fs::write(&path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn get(&self, filename: &str) -> Option<&Pin> {
+        self.pinned.get(filename)
+    }
+
+    pub fn pin(&mut self, filename: &str, note: Option<String>) {
+        let pin = Pin { pinned_at: Local::now().fixed_offset(), note };
+        self.pinned.insert(filename.to_string(), pin);
+    }
+
+    /// Removes the pin of a backup, returning whether it was pinned
+    pub fn unpin(&mut self, filename: &str) -> bool {
+        self.pinned.remove(filename).is_some()
+    }
+}
@@ -0,0 +1,461 @@
+//! Retention policies deciding which old backups `prune` deletes.
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Datelike, Duration, Local, Months};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::hash::Hash;
+use std::str::FromStr;
+
+use crate::backups::BackupEntry;
+
+/// Which backups of an island to keep. A backup kept by any rule is kept, and a policy without
+/// any rule keeps everything.
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    /// Keep the newest N backups
+    pub keep_last: Option<usize>,
+    /// Keep the newest backup of each day for the last D days
+    pub keep_daily: Option<u32>,
+    /// Keep the newest backup of each week for the last W weeks
+    pub keep_weekly: Option<u32>,
+    /// Keep the newest backup of each month for the last M months
+    pub keep_monthly: Option<u32>,
+    /// Delete the oldest kept backups until the island's backups fit into this size
+    pub max_total_size: Option<ByteSize>,
+}
+
+/// The `[retention]` table of the config file.
+///
+/// ```toml
+/// [retention]
+/// keep_last = 10
+/// keep_daily = 7
+/// keep_weekly = 4
+/// keep_monthly = 12
+/// max_total_size = "2GiB"
+///
+/// # Overrides for one island, by profile name or user id
+/// [retention.islands.Tom]
+/// keep_last = 30
+/// ```
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(default)]
+pub struct RetentionConfig {
+    /// Policy for every island without an override
+    #[serde(flatten)]
+    pub default: RetentionPolicy,
+    /// Overrides by profile name, user id or save folder id, falling back to the default per rule
+    pub islands: BTreeMap<String, RetentionPolicy>,
+}
+
+/// The backups of one save, identified by the user id in the manifest, or by the save folder id
+/// in the file name for backups without a manifest.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Island {
+    pub key: String,
+    pub profile_name: Option<String>,
+}
+
+/// Whether a backup is kept by the retention policy, and why.
+#[derive(Debug, Clone)]
+pub struct Decision {
+    pub keep: bool,
+    pub reason: String,
+}
+
+/// A size in bytes, written in the config file as a number of bytes or with a binary unit (`"500MiB"`, `"2G"`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteSize(pub u64);
+
+impl RetentionPolicy {
+    pub fn is_empty(&self) -> bool {
+        *self == RetentionPolicy::default()
+    }
+
+    /// Rules of `self`, with the ones it does not set taken from `fallback`
+    fn or(self, fallback: RetentionPolicy) -> RetentionPolicy {
+        RetentionPolicy {
+            keep_last: self.keep_last.or(fallback.keep_last),
+            keep_daily: self.keep_daily.or(fallback.keep_daily),
+            keep_weekly: self.keep_weekly.or(fallback.keep_weekly),
+            keep_monthly: self.keep_monthly.or(fallback.keep_monthly),
+            max_total_size: self.max_total_size.or(fallback.max_total_size),
+        }
+    }
+}
+
+impl fmt::Display for RetentionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "keep everything");
+        }
+        let mut rules = Vec::new();
+        if let Some(n) = self.keep_last {
+            rules.push(format!("last {n}"));
+        }
+        if let Some(days) = self.keep_daily {
+            rules.push(format!("daily for {days} days"));
+        }
+        if let Some(weeks) = self.keep_weekly {
+            rules.push(format!("weekly for {weeks} weeks"));
+        }
+        if let Some(months) = self.keep_monthly {
+            rules.push(format!("monthly for {months} months"));
+        }
+        if let Some(size) = self.max_total_size {
+            rules.push(format!("at most {size}"));
+        }
+        write!(f, "keep {}", rules.join(", "))
+    }
+}
+
+impl RetentionConfig {
+    /// Whether neither the default policy nor any island override has a rule
+    pub fn is_empty(&self) -> bool {
+        self.default.is_empty() && self.islands.values().all(RetentionPolicy::is_empty)
+    }
+
+    /// Policy for `island`: its override, with unset rules taken from the default policy
+    pub fn policy_for(&self, island: &Island) -> RetentionPolicy {
+        self.islands
+            .iter()
+            .find(|(selector, _)| island.matches(selector))
+            .map_or(self.default, |(_, policy)| policy.or(self.default))
+    }
+}
+
+impl Island {
+    /// Whether `selector` is the profile name (ignoring case) or the key of this island
+    pub fn matches(&self, selector: &str) -> bool {
+        self.profile_name.as_deref().is_some_and(|name| name.eq_ignore_ascii_case(selector))
+            || self.key.eq_ignore_ascii_case(selector)
+    }
+}
+
+impl fmt::Display for Island {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.profile_name {
+            Some(name) => write!(f, "{name} ({})", self.key),
+            None => f.write_str(&self.key),
+        }
+    }
+}
+
+/// Decides for each backup of one island whether it is kept.
+///
+/// `backups` must be sorted newest first, the decisions are returned in the same order. Pinned
+/// backups and the newest pre-restore snapshot (so the last restore can be undone) are always kept,
+/// and `max_total_size` never deletes the newest backup.
+pub fn plan(policy: &RetentionPolicy, backups: &[&BackupEntry], now: DateTime<Local>) -> Vec<Decision> {
+    if policy.is_empty() {
+        return backups
+            .iter()
+            .map(|backup| Decision {
+                keep: true,
+                reason: if backup.pin.is_some() { "pinned" } else { "no retention rule" }.to_string(),
+            })
+            .collect();
+    }
+
+    let mut reasons: Vec<Vec<String>> = backups
+        .iter()
+        .map(|backup| match &backup.pin {
+            Some(pin) => vec![pin.note.as_ref().map_or("pinned".to_string(), |note| format!("pinned: {note}"))],
+            None => Vec::new(),
+        })
+        .collect();
+
+    if let Some(n) = policy.keep_last {
+        for reasons in reasons.iter_mut().take(n) {
+            reasons.push(format!("last {n}"));
+        }
+    }
+    if let Some(days) = policy.keep_daily {
+        let since = now - Duration::days(days.into());
+        keep_per_period(&mut reasons, backups, since, "daily", |t| (t.year(), t.ordinal()));
+    }
+    if let Some(weeks) = policy.keep_weekly {
+        let since = now - Duration::weeks(weeks.into());
+        keep_per_period(&mut reasons, backups, since, "weekly", |t| (t.iso_week().year(), t.iso_week().week()));
+    }
+    if let Some(months) = policy.keep_monthly {
+        let since = now.checked_sub_months(Months::new(months)).unwrap_or(now);
+        keep_per_period(&mut reasons, backups, since, "monthly", |t| (t.year(), t.month()));
+    }
+    let has_keep_rule = policy.keep_last.is_some()
+        || policy.keep_daily.is_some()
+        || policy.keep_weekly.is_some()
+        || policy.keep_monthly.is_some();
+    if !has_keep_rule {
+        // Only a size limit is set, which keeps everything that fits
+        for reasons in &mut reasons {
+            reasons.push("within the size limit".to_string());
+        }
+    }
+    if let Some(i) = backups.iter().position(|backup| backup.is_pre_restore()) {
+        reasons[i].push("latest pre-restore snapshot".to_string());
+    }
+
+    let mut decisions: Vec<Decision> = reasons
+        .into_iter()
+        .map(|reasons| {
+            if reasons.is_empty() {
+                Decision { keep: false, reason: "not kept by any rule".to_string() }
+            } else {
+                Decision { keep: true, reason: reasons.join(", ") }
+            }
+        })
+        .collect();
+
+    if let Some(max) = policy.max_total_size {
+        let mut total: u64 = backups
+            .iter()
+            .zip(&decisions)
+            .filter(|(_, decision)| decision.keep)
+            .map(|(backup, _)| backup.size)
+            .sum();
+        // Drop the oldest kept backups first, but never the newest or a pinned one
+        for (backup, decision) in backups.iter().zip(decisions.iter_mut()).skip(1).rev() {
+            if total <= max.0 {
+                break;
+            }
+            if decision.keep && backup.pin.is_none() {
+                total -= backup.size;
+                *decision = Decision { keep: false, reason: format!("over the size limit of {max}") };
+            }
+        }
+    }
+
+    decisions
+}
+
+/// Marks the newest backup of every period since `since` as kept.
+fn keep_per_period<K: Hash + Eq>(
+    reasons: &mut [Vec<String>],
+    backups: &[&BackupEntry],
+    since: DateTime<Local>,
+    rule: &str,
+    period: impl Fn(&DateTime<Local>) -> K,
+) {
+    let mut seen = HashSet::new();
+    for (backup, reasons) in backups.iter().zip(reasons.iter_mut()) {
+        if backup.created >= since && seen.insert(period(&backup.created)) {
+            reasons.push(rule.to_string());
+        }
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let Ok(number) = number.parse::<f64>() else {
+            bail!("Invalid size {s}");
+        };
+        let factor: u64 = match unit.trim().to_lowercase().as_str() {
+            "" | "b" => 1,
+            "k" | "kb" | "kib" => 1 << 10,
+            "m" | "mb" | "mib" => 1 << 20,
+            "g" | "gb" | "gib" => 1 << 30,
+            "t" | "tb" | "tib" => 1 << 40,
+            other => bail!("Unknown size unit {other} in {s}"),
+        };
+        Ok(ByteSize((number * factor as f64) as u64))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+        if self.0 < 1 << 10 {
+            return write!(f, "{} B", self.0);
+        }
+        let mut size = self.0 as f64;
+        let mut unit = "B";
+        for next in UNITS {
+            if size < 1024.0 {
+                break;
+            }
+            size /= 1024.0;
+            unit = next;
+        }
+        write!(f, "{size:.1} {unit}")
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Bytes(u64),
+            Text(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Bytes(bytes) => Ok(ByteSize(bytes)),
+            Raw::Text(text) => text.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::path::PathBuf;
+
+    use crate::pins::Pin;
+    use crate::backups::PRE_RESTORE_NAME;
+
+    /// Saturday, 2024-06-15 12:00
+    fn now() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 6, 15, 12, 0, 0).earliest().unwrap()
+    }
+
+    /// A backup created `hours` before [`now`]
+    struct Backup {
+        hours: i64,
+        size: u64,
+        pinned: bool,
+        pre_restore: bool,
+    }
+
+    fn at(hours: i64) -> Backup {
+        Backup { hours, size: 100, pinned: false, pre_restore: false }
+    }
+
+    impl Backup {
+        fn pinned(self) -> Backup {
+            Backup { pinned: true, ..self }
+        }
+
+        fn pre_restore(self) -> Backup {
+            Backup { pre_restore: true, ..self }
+        }
+
+        fn size(self, size: u64) -> Backup {
+            Backup { size, ..self }
+        }
+
+        fn entry(&self, i: usize) -> BackupEntry {
+            let name = if self.pre_restore { PRE_RESTORE_NAME } else { "Backup" };
+            let filename = format!("0000000000000001_{name}_{i}.zip");
+            BackupEntry {
+                path: PathBuf::from(&filename),
+                filename,
+                name: Some(name.to_string()),
+                created: now() - Duration::hours(self.hours),
+                size: self.size,
+                manifest: None,
+                pin: self.pinned.then(|| Pin { pinned_at: now().fixed_offset(), note: None }),
+            }
+        }
+    }
+
+    const DAY: i64 = 24;
+
+    #[test]
+    fn plan_keeps_backups_by_rule() {
+        let policy = |f: fn(&mut RetentionPolicy)| {
+            let mut policy = RetentionPolicy::default();
+            f(&mut policy);
+            policy
+        };
+        let cases: Vec<(&str, RetentionPolicy, Vec<Backup>, Vec<bool>)> = vec![
+            ("no rule", policy(|_| {}), vec![at(1), at(2 * DAY), at(400 * DAY)], vec![true, true, true]),
+            ("keep_last", policy(|p| p.keep_last = Some(2)), vec![at(1), at(2), at(3), at(4)], vec![true, true, false, false]),
+            (
+                "keep_daily",
+                policy(|p| p.keep_daily = Some(3)),
+                vec![at(1), at(2), at(DAY), at(2 * DAY), at(5 * DAY)],
+                vec![true, false, true, true, false],
+            ),
+            (
+                "keep_weekly",
+                policy(|p| p.keep_weekly = Some(2)),
+                vec![at(DAY), at(3 * DAY), at(7 * DAY), at(20 * DAY)],
+                vec![true, false, true, false],
+            ),
+            (
+                "keep_monthly",
+                policy(|p| p.keep_monthly = Some(2)),
+                vec![at(DAY), at(20 * DAY), at(25 * DAY), at(70 * DAY)],
+                vec![true, true, false, false],
+            ),
+            (
+                "rules combine",
+                policy(|p| {
+                    p.keep_last = Some(1);
+                    p.keep_monthly = Some(12);
+                }),
+                vec![at(1), at(2), at(40 * DAY), at(45 * DAY)],
+                vec![true, false, true, false],
+            ),
+            (
+                "pinned",
+                policy(|p| p.keep_last = Some(1)),
+                vec![at(1), at(2).pinned(), at(3)],
+                vec![true, true, false],
+            ),
+            (
+                "newest pre-restore",
+                policy(|p| p.keep_last = Some(1)),
+                vec![at(1), at(2).pre_restore(), at(3).pre_restore(), at(4)],
+                vec![true, true, false, false],
+            ),
+            (
+                "size limit",
+                policy(|p| {
+                    p.keep_last = Some(10);
+                    p.max_total_size = Some(ByteSize(250));
+                }),
+                vec![at(1), at(2), at(3), at(4)],
+                vec![true, true, false, false],
+            ),
+            (
+                "size limit keeps newest",
+                policy(|p| p.max_total_size = Some(ByteSize(50))),
+                vec![at(1), at(2)],
+                vec![true, false],
+            ),
+            (
+                "size limit keeps pinned",
+                policy(|p| p.max_total_size = Some(ByteSize(150))),
+                vec![at(1), at(2), at(3).pinned().size(100)],
+                vec![true, false, true],
+            ),
+        ];
+
+        for (name, policy, backups, expected) in cases {
+            let entries: Vec<BackupEntry> = backups.iter().enumerate().map(|(i, backup)| backup.entry(i)).collect();
+            let entries: Vec<&BackupEntry> = entries.iter().collect();
+            let keep: Vec<bool> = plan(&policy, &entries, now()).iter().map(|decision| decision.keep).collect();
+            assert_eq!(keep, expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn byte_sizes() {
+        let cases = [
+            ("1024", Some(1024)),
+            ("1.5 KiB", Some(1536)),
+            ("10kb", Some(10 << 10)),
+            ("500MiB", Some(500 << 20)),
+            ("2G", Some(2 << 30)),
+            ("1 TiB", Some(1 << 40)),
+            ("", None),
+            ("abc", None),
+            ("5 PB", None),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(text.parse::<ByteSize>().ok().map(|size| size.0), expected, "{text}");
+        }
+    }
+}
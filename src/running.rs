@@ -0,0 +1,147 @@
+//! Detection of running emulators.
+//!
+//! A running emulator keeps the save open: it overwrites a restored save on its next autosave, and a
+//! backup taken while it writes can capture half of a save. Processes are only found on Linux, by
+//! their name in `/proc`.
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use crate::emulator::Emulator;
+
+/// How often the process list is checked while waiting for an emulator to exit
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What to do when the emulator of the save is running before a backup or restore.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum IfRunning {
+    /// Print a warning and go ahead
+    Warn,
+    /// Stop with an error
+    Refuse,
+    /// Wait until the emulator has exited
+    Wait,
+}
+
+impl IfRunning {
+    pub fn name(self) -> &'static str {
+        match self {
+            IfRunning::Warn => "warn",
+            IfRunning::Refuse => "refuse",
+            IfRunning::Wait => "wait",
+        }
+    }
+}
+
+impl fmt::Display for IfRunning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for IfRunning {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "warn" => Ok(IfRunning::Warn),
+            "refuse" => Ok(IfRunning::Refuse),
+            "wait" => Ok(IfRunning::Wait),
+            other => bail!("Unknown running emulator policy {other}, expected warn, refuse or wait"),
+        }
+    }
+}
+
+/// An emulator process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunningEmulator {
+    pub emulator: Emulator,
+    pub pid: u32,
+}
+
+impl fmt::Display for RunningEmulator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (pid {})", self.emulator, self.pid)
+    }
+}
+
+/// Emulator owning a process named `name`, e.g. `Ryujinx`, `yuzu-cmd` or `Ryujinx.AppImage`
+fn emulator_of_process(name: &str) -> Option<Emulator> {
+    let name = name.to_lowercase();
+    match name.split('.').next().unwrap_or_default() {
+        "ryujinx" | "ryubing" => Some(Emulator::Ryujinx),
+        "yuzu" | "yuzu-cmd" => Some(Emulator::Yuzu),
+        "suyu" | "suyu-cmd" => Some(Emulator::Suyu),
+        "sudachi" | "sudachi-cmd" => Some(Emulator::Sudachi),
+        _ => None,
+    }
+}
+
+/// Running processes of `emulator`, by the process name or the file name of the executable.
+#[cfg(target_os = "linux")]
+pub fn running(emulator: Emulator) -> Vec<RunningEmulator> {
+    use std::fs;
+    use std::path::Path;
+
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    let mut found = Vec::new();
+    for entry in entries.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) else {
+            continue;
+        };
+        if pid == std::process::id() {
+            continue;
+        }
+        // Processes can exit while they are read, those are skipped
+        let comm = fs::read_to_string(entry.path().join("comm")).unwrap_or_default();
+        let cmdline = fs::read(entry.path().join("cmdline")).unwrap_or_default();
+        let executable = cmdline.split(|&byte| byte == 0).next().unwrap_or_default();
+        let executable = String::from_utf8_lossy(executable);
+        let executable = Path::new(executable.as_ref()).file_name().unwrap_or_default().to_string_lossy();
+        if [comm.trim(), executable.as_ref()].into_iter().any(|name| emulator_of_process(name) == Some(emulator)) {
+            found.push(RunningEmulator { emulator, pid });
+        }
+    }
+    found.sort_by_key(|process| process.pid);
+    found
+}
+
+/// Running processes of `emulator`. Processes can only be listed on Linux, elsewhere none are found.
+#[cfg(not(target_os = "linux"))]
+pub fn running(_emulator: Emulator) -> Vec<RunningEmulator> {
+    Vec::new()
+}
+
+/// Makes sure `emulator` is not running before `action` (e.g. "restoring"), handling a running
+/// emulator according to `policy`.
+pub fn check_not_running(emulator: Emulator, policy: IfRunning, action: &str) -> Result<()> {
+    let processes = running(emulator);
+    if processes.is_empty() {
+        return Ok(());
+    }
+    let list: Vec<String> = processes.iter().map(RunningEmulator::to_string).collect();
+    let list = list.join(", ");
+    match policy {
+        IfRunning::Warn => {
+            println!("Warning: {list} is running, it may write to the save while {action}");
+            Ok(())
+        }
+        IfRunning::Refuse => bail!(
+            "{list} is running, close it before {action} or use --if-running wait to wait until it exits"
+        ),
+        IfRunning::Wait => {
+            println!("Waiting for {list} to exit before {action}, press Ctrl+C to cancel");
+            while !running(emulator).is_empty() {
+                thread::sleep(POLL_INTERVAL);
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! Reading Ryujinx's save data metadata to find out which `bis/user/save` folder belongs to which game.
+//!
+//! Ryujinx names save folders after the save data id, which is assigned in order of creation.
+//! The mapping from title to save data id is kept in the save data index of the file system
+//! service (`bis/system/save/8000000000000000/0/imkvdb.arc`), and every save folder additionally
+//! carries a copy of its attribute in `ExtraData0`/`ExtraData1`.
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::emulator::Profile;
+
+/// Size of a `SaveDataAttribute`, the key of the save data index and the start of `ExtraData`
+const ATTRIBUTE_SIZE: usize = 0x40;
+
+/// A game save found in the Ryujinx save directory.
+#[derive(Debug, Clone)]
+pub struct SaveData {
+    /// Save data id, the folder name in `bis/user/save`
+    pub save_id: u64,
+    pub title_id: u64,
+    /// Switch user profile UUID, zero for saves not tied to a user
+    pub user_id: u128,
+    /// Folder holding the save
+    pub path: PathBuf,
+}
+
+/// Part of a `SaveDataAttribute` that identifies a save.
+struct Attribute {
+    title_id: u64,
+    user_id: u128,
+}
+
+fn parse_attribute(data: &[u8]) -> Result<Attribute> {
+    if data.len() < ATTRIBUTE_SIZE {
+        bail!("Save data attribute too short ({} bytes)", data.len());
+    }
+    Ok(Attribute {
+        title_id: u64::from_le_bytes(data[0x00..0x08].try_into()?),
+        user_id: read_user_id(&data[0x08..0x18]),
+    })
+}
+
+/// A user id is stored as two little endian u64, high half first.
+fn read_user_id(data: &[u8]) -> u128 {
+    let high = u64::from_le_bytes(data[0..8].try_into().unwrap());
+    let low = u64::from_le_bytes(data[8..16].try_into().unwrap());
+    ((high as u128) << 64) | low as u128
+}
+
+/// Formats a user id the way Ryujinx writes it in `profiles.json`, as 32 lowercase hex digits.
+pub fn format_user_id(user_id: u128) -> String {
+    format!("{user_id:032x}")
+}
+
+/// Directory holding the user saves of a Ryujinx installation
+pub fn user_save_dir(ryujinx_dir: &Path) -> PathBuf {
+    ryujinx_dir.join("bis").join("user").join("save")
+}
+
+fn index_path(ryujinx_dir: &Path) -> PathBuf {
+    ryujinx_dir
+        .join("bis")
+        .join("system")
+        .join("save")
+        .join("8000000000000000")
+        .join("0")
+        .join("imkvdb.arc")
+}
+
+/// Parses the save data index (`imkvdb.arc`).
+///
+/// The file starts with an `IMKV` header (magic, reserved, entry count), followed by `IMEN` entries
+/// of key size, value size, a `SaveDataAttribute` key and a `SaveDataIndexerValue` value, which
+/// starts with the save data id.
+fn parse_index(data: &[u8]) -> Result<Vec<(Attribute, u64)>> {
+    let read_u32 = |offset: usize| -> Result<u32> {
+        let bytes = data.get(offset..offset + 4).context("Unexpected end of save data index")?;
+        Ok(u32::from_le_bytes(bytes.try_into()?))
+    };
+
+    if data.get(0..4) != Some(b"IMKV".as_slice()) {
+        bail!("Not a save data index, missing IMKV magic");
+    }
+    let count = read_u32(8)? as usize;
+
+    let mut entries = Vec::with_capacity(count);
+    let mut offset = 0xC;
+    for _ in 0..count {
+        if data.get(offset..offset + 4) != Some(b"IMEN".as_slice()) {
+            bail!("Corrupt save data index, missing IMEN magic at {offset:#x}");
+        }
+        let key_size = read_u32(offset + 4)? as usize;
+        let value_size = read_u32(offset + 8)? as usize;
+        let key_start = offset + 0xC;
+        let value_start = key_start + key_size;
+        let end = value_start + value_size;
+        if key_size < ATTRIBUTE_SIZE || value_size < 8 || end > data.len() {
+            bail!("Corrupt save data index entry at {offset:#x}");
+        }
+
+        let attribute = parse_attribute(&data[key_start..value_start])?;
+        let save_id = u64::from_le_bytes(data[value_start..value_start + 8].try_into()?);
+        entries.push((attribute, save_id));
+        offset = end;
+    }
+    Ok(entries)
+}
+
+/// Finds all game saves of a Ryujinx installation.
+///
+/// The save data index is used when present, otherwise every save folder's `ExtraData` is read.
+pub fn find_saves(ryujinx_dir: &Path) -> Result<Vec<SaveData>> {
+    let save_dir = user_save_dir(ryujinx_dir);
+    let index = index_path(ryujinx_dir);
+
+    let mut saves = if index.exists() {
+        let data = fs::read(&index).with_context(|| format!("Failed to read {}", index.display()))?;
+        parse_index(&data)
+            .with_context(|| format!("Failed to parse {}", index.display()))?
+            .into_iter()
+            .map(|(attribute, save_id)| SaveData {
+                save_id,
+                title_id: attribute.title_id,
+                user_id: attribute.user_id,
+                path: save_dir.join(format!("{save_id:016x}")),
+            })
+            .filter(|save| save.path.is_dir())
+            .collect()
+    } else {
+        scan_extra_data(&save_dir)?
+    };
+
+    saves.sort_by_key(|save| save.save_id);
+    Ok(saves)
+}
+
+/// Reads the attribute from the `ExtraData` of every save folder.
+fn scan_extra_data(save_dir: &Path) -> Result<Vec<SaveData>> {
+    if !save_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut saves = Vec::new();
+    for entry in fs::read_dir(save_dir).with_context(|| format!("Failed to read {}", save_dir.display()))? {
+        let path = entry?.path();
+        let Some(save_id) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .filter(|name| name.len() == 16)
+            .and_then(|name| u64::from_str_radix(name, 16).ok())
+        else {
+            continue;
+        };
+
+        if let Some(attribute) = read_extra_data(&path) {
+            saves.push(SaveData {
+                save_id,
+                title_id: attribute.title_id,
+                user_id: attribute.user_id,
+                path,
+            });
+        }
+    }
+    Ok(saves)
+}
+
+/// Reads the attribute of a save folder from its `ExtraData0` (or `ExtraData1`).
+fn read_extra_data(save_dir: &Path) -> Option<Attribute> {
+    ["ExtraData0", "ExtraData1"]
+        .iter()
+        .filter_map(|name| fs::read(save_dir.join(name)).ok())
+        .find_map(|data| parse_attribute(&data).ok())
+}
+
+/// Reads the user of a save folder from its `ExtraData`.
+pub fn read_save_user(save_dir: &Path) -> Option<u128> {
+    read_extra_data(save_dir)
+        .map(|attribute| attribute.user_id)
+        .filter(|&user_id| user_id != 0)
+}
+
+#[derive(Deserialize)]
+struct ProfilesFile {
+    profiles: Vec<ProfileEntry>,
+}
+
+#[derive(Deserialize)]
+struct ProfileEntry {
+    user_id: String,
+    name: String,
+}
+
+/// Reads the user profiles from `system/Profiles.json`.
+pub fn read_profiles(ryujinx_dir: &Path) -> Result<Vec<Profile>> {
+    let system_dir = ryujinx_dir.join("system");
+    let Some(path) = ["Profiles.json", "profiles.json"]
+        .iter()
+        .map(|name| system_dir.join(name))
+        .find(|path| path.is_file())
+    else {
+        return Ok(Vec::new());
+    };
+
+    let content = fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let file: ProfilesFile =
+        serde_json::from_str(&content).with_context(|| format!("Failed to parse {}", path.display()))?;
+
+    file.profiles
+        .into_iter()
+        .map(|profile| {
+            let user_id = u128::from_str_radix(&profile.user_id, 16)
+                .with_context(|| format!("Invalid user id {} in {}", profile.user_id, path.display()))?;
+            Ok(Profile { user_id, name: profile.name })
+        })
+        .collect()
+}
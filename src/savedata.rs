@@ -0,0 +1,437 @@
+//! Decryption of the ACNH save files.
+//!
+//! Every `<name>.dat` file of a save, like `main.dat` or `Villager0/personal.dat`, is encrypted with
+//! AES-128-CTR. Key and counter are derived from the sibling `<name>Header.dat` with the SEAD random
+//! generator of the game. The decrypted files are split into regions that each start with a Murmur3
+//! hash of the rest of the region, which the game checks when loading the save. The regions differ
+//! between game versions, those of unknown versions are found by following the hashes.
+
+use aes::cipher::{KeyIvInit, StreamCipher};
+use anyhow::{bail, Context, Result};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::archive::entry_name;
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+
+/// Size of a `*Header.dat` file
+pub const HEADER_SIZE: usize = 0x300;
+
+/// Offset of the key material in a header, the part before it holds the revision
+const KEY_DATA_OFFSET: usize = 0x100;
+
+/// Suffix of the header file belonging to a save file
+const HEADER_SUFFIX: &str = "Header.dat";
+
+/// Revision of the save format, stored at the start of every header and decrypted file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Revision {
+    pub major: u32,
+    pub minor: u32,
+    pub header_revision: u16,
+    pub save_revision: u16,
+}
+
+impl Revision {
+    fn parse(data: &[u8]) -> Revision {
+        Revision {
+            major: read_u32(data, 0),
+            minor: read_u32(data, 4),
+            header_revision: u16::from_le_bytes([data[10], data[11]]),
+            save_revision: u16::from_le_bytes([data[14], data[15]]),
+        }
+    }
+}
+
+impl fmt::Display for Revision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}.{:#x} (header {}, save {})", self.major, self.minor, self.header_revision, self.save_revision)
+    }
+}
+
+/// A decrypted save file.
+#[derive(Debug, Clone)]
+pub struct SaveFile {
+    /// Path relative to the save folder, with `/` separators
+    pub name: String,
+    pub revision: Revision,
+    pub data: Vec<u8>,
+}
+
+/// Result of checking the Murmur3 hashes of a decrypted file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Checksums {
+    /// All regions match their hash
+    Valid(usize),
+    /// Offsets of the regions whose hash does not match
+    Invalid(Vec<usize>),
+    /// The regions up to `unknown_from` match their hash, the data after it is no region that can be
+    /// found. Either the save is damaged there or the file has a layout that cannot be followed.
+    Incomplete { valid: usize, unknown_from: usize },
+    /// No hashed region was found in the file, nothing was checked
+    UnknownLayout,
+}
+
+impl fmt::Display for Checksums {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Checksums::Valid(regions) => write!(f, "checksums OK ({regions} regions)"),
+            Checksums::Invalid(offsets) => {
+                let offsets: Vec<String> = offsets.iter().map(|offset| format!("{offset:#x}")).collect();
+                write!(f, "checksum mismatch at {}", offsets.join(", "))
+            }
+            Checksums::Incomplete { valid, unknown_from } => {
+                write!(f, "checksums OK for {valid} regions, no valid region found at {unknown_from:#x}")
+            }
+            Checksums::UnknownLayout => write!(f, "checksums not checked, unknown file layout"),
+        }
+    }
+}
+
+impl SaveFile {
+    /// Checks the Murmur3 hash of every region of the file
+    pub fn checksums(&self) -> Checksums {
+        let Some(regions) = hash_regions(&self.name, self.data.len()) else {
+            return match find_regions(&self.data) {
+                (regions, _) if regions.is_empty() => Checksums::UnknownLayout,
+                (regions, None) => Checksums::Valid(regions.len()),
+                (regions, Some(unknown_from)) => Checksums::Incomplete { valid: regions.len(), unknown_from },
+            };
+        };
+        let invalid: Vec<usize> = regions
+            .iter()
+            .filter(|region| {
+                let stored = read_u32(&self.data, region.offset);
+                let data = &self.data[region.offset + 4..region.offset + 4 + region.size];
+                murmur3(data, 0) != stored
+            })
+            .map(|region| region.offset)
+            .collect();
+        if invalid.is_empty() {
+            Checksums::Valid(regions.len())
+        } else {
+            Checksums::Invalid(invalid)
+        }
+    }
+}
+
+/// Decrypts every save file in `dir`, the folder holding `main.dat`.
+///
+/// Files without a header next to them are not encrypted (like `landname.dat`) and are left out.
+pub fn decrypt_save(dir: &Path) -> Result<Vec<SaveFile>> {
+    let main = dir.join("main.dat");
+    if !main.is_file() {
+        bail!("No main.dat in {}", dir.display());
+    }
+    if !header_path(&main).is_file() {
+        bail!("No mainHeader.dat in {}, the save is incomplete", dir.display());
+    }
+    let mut files = Vec::new();
+    for entry in WalkDir::new(dir).min_depth(1).sort_by_file_name() {
+        let entry = entry?;
+        let path = entry.path();
+        let file_name = entry.file_name().to_string_lossy();
+        if !entry.file_type().is_file() || !file_name.ends_with(".dat") || is_header(&file_name) {
+            continue;
+        }
+        if !header_path(path).is_file() {
+            continue;
+        }
+        files.push(read_save_file(dir, &entry_name(path.strip_prefix(dir)?))?);
+    }
+    Ok(files)
+}
+
+/// Reads and decrypts the save file `name` of `dir`, e.g. `main.dat`
+pub fn read_save_file(dir: &Path, name: &str) -> Result<SaveFile> {
+    let path = dir.join(name);
+    let header_path = header_path(&path);
+    let header = fs::read(&header_path).with_context(|| format!("Failed to read {}", header_path.display()))?;
+    let data = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let data = decrypt(&header, data).with_context(|| format!("Failed to decrypt {name}"))?;
+    Ok(SaveFile { name: name.to_string(), revision: Revision::parse(&header), data })
+}
+
+/// Whether `name` is the header of another save file
+pub fn is_header(name: &str) -> bool {
+    name.ends_with(HEADER_SUFFIX)
+}
+
+/// `<name>Header.dat` next to `<name>.dat`
+pub fn header_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{stem}{HEADER_SUFFIX}"))
+}
+
+/// Decrypts the contents of a save file with the key material of its header.
+pub fn decrypt(header: &[u8], mut data: Vec<u8>) -> Result<Vec<u8>> {
+    if header.len() < HEADER_SIZE {
+        bail!("Header is {} bytes, expected {HEADER_SIZE}", header.len());
+    }
+    let key_data: Vec<u32> = (0..0x80).map(|i| read_u32(header, KEY_DATA_OFFSET + i * 4)).collect();
+    let key = derive_param(&key_data, 0);
+    let counter = derive_param(&key_data, 2);
+    Aes128Ctr::new(&key.into(), &counter.into()).apply_keystream(&mut data);
+    Ok(data)
+}
+
+/// Derives the key (`index` 0) or the counter (`index` 2) from the key material of a header
+fn derive_param(key_data: &[u32], index: usize) -> [u8; 16] {
+    let mut random = SeadRandom::new(key_data[(key_data[index] & 0x7F) as usize]);
+    let rounds = (key_data[(key_data[index + 1] & 0x7F) as usize] & 0xF) + 1;
+    for _ in 0..rounds {
+        random.next_u64();
+    }
+    let mut param = [0; 16];
+    for byte in &mut param {
+        *byte = (random.next_u32() >> 24) as u8;
+    }
+    param
+}
+
+/// The xorshift based random generator of Nintendo's SEAD library.
+struct SeadRandom {
+    state: [u32; 4],
+}
+
+impl SeadRandom {
+    fn new(seed: u32) -> SeadRandom {
+        let mut state = [0; 4];
+        let mut previous = seed;
+        for (i, value) in state.iter_mut().enumerate() {
+            *value = 0x6C07_8965u32.wrapping_mul(previous ^ (previous >> 30)).wrapping_add(i as u32 + 1);
+            previous = *value;
+        }
+        SeadRandom { state }
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        let a = self.state[0] ^ (self.state[0] << 11);
+        let b = self.state[3];
+        let next = a ^ (a >> 8) ^ b ^ (b >> 19);
+        self.state = [self.state[1], self.state[2], self.state[3], next];
+        next
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        (u64::from(self.next_u32()) << 32) | u64::from(self.next_u32())
+    }
+}
+
+/// 32-bit Murmur3 hash, as used for the save regions
+pub fn murmur3(data: &[u8], seed: u32) -> u32 {
+    let mut hash = seed;
+    let mut blocks = data.chunks_exact(4);
+    for block in &mut blocks {
+        hash = murmur3_block(hash, u32::from_le_bytes(block.try_into().unwrap()));
+    }
+    let rest = blocks.remainder();
+    if !rest.is_empty() {
+        let mut last = [0; 4];
+        last[..rest.len()].copy_from_slice(rest);
+        hash ^= scramble(u32::from_le_bytes(last));
+    }
+    murmur3_finish(hash, data.len())
+}
+
+/// Mixes the next four bytes into a Murmur3 hash
+fn murmur3_block(hash: u32, block: u32) -> u32 {
+    (hash ^ scramble(block)).rotate_left(13).wrapping_mul(5).wrapping_add(0xE654_6B64)
+}
+
+/// Final mix of a Murmur3 hash over `len` bytes
+fn murmur3_finish(mut hash: u32, len: usize) -> u32 {
+    hash ^= len as u32;
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85EB_CA6B);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xC2B2_AE35);
+    hash ^ (hash >> 16)
+}
+
+fn scramble(k: u32) -> u32 {
+    k.wrapping_mul(0xCC9E_2D51).rotate_left(15).wrapping_mul(0x1B87_3593)
+}
+
+/// A region of a decrypted file: the Murmur3 hash at `offset`, followed by the `size` bytes it covers.
+struct HashRegion {
+    offset: usize,
+    size: usize,
+}
+
+/// Size of the decrypted `main.dat` of game version 1.0.0
+const MAIN_SIZE_100: usize = 0xAC0938;
+/// Size of the decrypted `personal.dat` of game version 1.0.0
+const PERSONAL_SIZE_100: usize = 0x6BED0;
+
+/// Hashed regions of a decrypted file, `None` if the layout of this file and size is unknown.
+///
+/// The layout differs between game versions, the known ones are recognized by their file size.
+fn hash_regions(name: &str, size: usize) -> Option<Vec<HashRegion>> {
+    // Every player in main.dat, and personal.dat, consists of two hashed regions
+    let player = |offset: usize| [HashRegion { offset, size: 0x35AFC }, HashRegion { offset: offset + 0x35B00, size: 0x362BC }];
+    let file_name = name.rsplit('/').next().unwrap_or(name);
+    match (file_name, size) {
+        ("main.dat", MAIN_SIZE_100) => {
+            let mut regions = vec![
+                HashRegion { offset: 0x108, size: 0x1D6D4C },
+                HashRegion { offset: 0x1D6E58, size: 0x323384 },
+            ];
+            regions.extend((0..8).flat_map(|i| player(0x4FA2E8 + i * 0x6BDC0)));
+            Some(regions)
+        }
+        ("personal.dat", PERSONAL_SIZE_100) => Some(player(0x108).into()),
+        _ => None,
+    }
+}
+
+/// Offsets the first region of a file can start at: after the revision and 8 bytes, or right after the revision
+const FIRST_REGION_OFFSETS: [usize; 2] = [0x108, 0x100];
+
+/// Gap before a new section of regions, like a player in `main.dat`
+const SECTION_GAP: usize = 0x108;
+
+/// Trailing bytes after the last region that are not hashed
+const MAX_TRAILER: usize = 0x10;
+
+/// Finds the hashed regions of a file of unknown layout by following them from the first one. A
+/// region ends where the Murmur3 hash of the bytes read so far matches its stored hash, and the next
+/// one starts right after it or after a section gap.
+///
+/// Returns the regions found and, if they do not reach the end of the file, the offset after which
+/// no further region was found.
+fn find_regions(data: &[u8]) -> (Vec<HashRegion>, Option<usize>) {
+    let mut regions: Vec<HashRegion> = Vec::new();
+    let mut candidates = FIRST_REGION_OFFSETS.to_vec();
+    loop {
+        let region = candidates
+            .iter()
+            .find_map(|&offset| region_size(data, offset).map(|size| HashRegion { offset, size }));
+        let Some(region) = region else {
+            return (regions, Some(candidates[0]));
+        };
+        let end = region.offset + 4 + region.size;
+        regions.push(region);
+        if data.len() - end <= MAX_TRAILER {
+            return (regions, None);
+        }
+        candidates = vec![end, end + SECTION_GAP];
+    }
+}
+
+/// Size of the region whose hash is stored at `offset`: the shortest run of whole blocks after the hash
+/// whose Murmur3 hash matches it, `None` if there is none
+fn region_size(data: &[u8], offset: usize) -> Option<usize> {
+    if offset + 8 > data.len() {
+        return None;
+    }
+    let stored = read_u32(data, offset);
+    let start = offset + 4;
+    let mut hash = 0;
+    for (i, block) in data[start..].chunks_exact(4).enumerate() {
+        hash = murmur3_block(hash, u32::from_le_bytes(block.try_into().unwrap()));
+        let size = (i + 1) * 4;
+        if murmur3_finish(hash, size) == stored {
+            return Some(size);
+        }
+    }
+    None
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Header whose key material is a fixed byte pattern
+    fn test_header() -> Vec<u8> {
+        (0..HEADER_SIZE).map(|i| (i * 7 + 3) as u8).collect()
+    }
+
+    #[test]
+    fn murmur3_reference_vectors() {
+        let cases: [(&[u8], u32, u32); 8] = [
+            (b"", 0, 0),
+            (b"", 1, 0x514E_28B7),
+            (b"\0\0\0\0", 0, 0x2362_F9DE),
+            (b"a", 0x9747_B28C, 0x7FA0_9EA6),
+            (b"abc", 0x9747_B28C, 0xC84A_62DD),
+            (b"hello", 0, 0x248B_FA47),
+            (b"Hello, world!", 0x9747_B28C, 0x2488_4CBA),
+            (b"The quick brown fox jumps over the lazy dog", 0x9747_B28C, 0x2FA8_26CD),
+        ];
+        for (data, seed, expected) in cases {
+            assert_eq!(murmur3(data, seed), expected, "{:?}", String::from_utf8_lossy(data));
+        }
+    }
+
+    #[test]
+    fn sead_random() {
+        let mut random = SeadRandom::new(0x1234_5678);
+        let values: Vec<u32> = (0..4).map(|_| random.next_u32()).collect();
+        assert_eq!(values, [0x4BB8_78EE, 0x5C06_6D33, 0x130A_D179, 0x872A_FC5C]);
+
+        let mut random = SeadRandom::new(0);
+        assert_eq!(random.next_u64(), 0x4807_714D_181B_859B);
+    }
+
+    #[test]
+    fn key_derivation() {
+        let header = test_header();
+        let key_data: Vec<u32> = (0..0x80).map(|i| read_u32(&header, KEY_DATA_OFFSET + i * 4)).collect();
+        assert_eq!(hex::encode(derive_param(&key_data, 0)), "b788c5dac63ba020a5a4d2e3f87d42ea");
+        assert_eq!(hex::encode(derive_param(&key_data, 2)), "8cbe711ba116bf6916c064117354603c");
+    }
+
+    #[test]
+    fn decrypt_known_data() {
+        let encrypted = hex::decode("d3f70c018c342eeef5721dc102fe3f5d666261305db954809e987736ac9929f0").unwrap();
+        let decrypted = decrypt(&test_header(), encrypted).unwrap();
+        assert_eq!(decrypted, b"Animal Crossing: New Horizons!!!");
+        assert!(decrypt(&[0; 0x100], Vec::new()).is_err());
+    }
+
+    /// Builds decrypted file contents with a region at each `(offset, size)`, filled with a byte pattern
+    fn file_with_regions(len: usize, regions: &[(usize, usize)]) -> Vec<u8> {
+        let mut data: Vec<u8> = (0..len).map(|i| (i * 31 % 251) as u8).collect();
+        for &(offset, size) in regions {
+            let hash = murmur3(&data[offset + 4..offset + 4 + size], 0);
+            data[offset..offset + 4].copy_from_slice(&hash.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn find_regions_of_unknown_layout() {
+        // Two regions, a section gap, two more and a short trailer, like a newer main.dat
+        let layout = [(0x108, 0x1000), (0x110C, 0x2FC), (0x1514, 0x800), (0x1D18, 0x400)];
+        let data = file_with_regions(0x2120, &layout);
+        let file = SaveFile { name: "main.dat".to_string(), revision: Revision::parse(&data), data };
+        assert_eq!(file.checksums(), Checksums::Valid(4));
+
+        let mut damaged = file.clone();
+        damaged.data[0x1600] ^= 1;
+        assert_eq!(damaged.checksums(), Checksums::Incomplete { valid: 2, unknown_from: 0x140C });
+
+        let random = SaveFile { data: file_with_regions(0x2000, &[]), ..file };
+        assert_eq!(random.checksums(), Checksums::UnknownLayout);
+    }
+
+    #[test]
+    fn known_layout_reports_damaged_regions() {
+        let regions = hash_regions("Villager0/personal.dat", PERSONAL_SIZE_100).unwrap();
+        let layout: Vec<(usize, usize)> = regions.iter().map(|region| (region.offset, region.size)).collect();
+        let data = file_with_regions(PERSONAL_SIZE_100, &layout);
+        let mut file = SaveFile { name: "Villager0/personal.dat".to_string(), revision: Revision::parse(&data), data };
+        assert_eq!(file.checksums(), Checksums::Valid(2));
+
+        file.data[0x40000] ^= 1;
+        assert_eq!(file.checksums(), Checksums::Invalid(vec![0x35C08]));
+    }
+}
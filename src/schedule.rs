@@ -0,0 +1,86 @@
+//! Scheduled backups on a fixed interval or a cron expression.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use walkdir::WalkDir;
+
+use crate::emulator::SaveLocation;
+
+/// When scheduled backups run.
+#[derive(Debug, Clone)]
+pub enum Timer {
+    Every(Duration),
+    Cron(Box<cron::Schedule>),
+}
+
+impl Timer {
+    /// Time of the next run after `now`, `None` if a cron expression has no upcoming run
+    fn next_run(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        match self {
+            Timer::Every(interval) => Some(now + *interval),
+            Timer::Cron(schedule) => schedule.after(&now).next(),
+        }
+    }
+}
+
+impl fmt::Display for Timer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Timer::Every(interval) => write!(f, "every {}", humantime::format_duration(*interval)),
+            Timer::Cron(schedule) => write!(f, "on cron schedule {schedule}"),
+        }
+    }
+}
+
+/// Parses a cron expression. The seconds field is optional, so the usual five field
+/// expressions (`0 */2 * * *`) work as well.
+pub fn parse_cron(expression: &str) -> Result<Box<cron::Schedule>, String> {
+    let expression = match expression.split_whitespace().count() {
+        5 => format!("0 {expression}"),
+        _ => expression.to_string(),
+    };
+    cron::Schedule::from_str(&expression).map(Box::new).map_err(|e| e.to_string())
+}
+
+/// Runs `backup` whenever `timer` fires. Runs until the process is stopped.
+///
+/// Like in watch mode, the `if_running` setting is not applied, as scheduled backups are meant to
+/// run while the game is played. A run that fires while the emulator is writing the save waits
+/// until no file of the save has been written to for `quiet` instead.
+pub fn schedule(save: &SaveLocation, timer: &Timer, quiet: Duration, mut backup: impl FnMut()) -> Result<()> {
+    println!("Backing up {} {timer}, press Ctrl+C to stop", save.dir.display());
+    loop {
+        let next = timer.next_run(Local::now()).context("The cron expression has no upcoming run")?;
+        println!("Next backup at {}", next.format("%Y-%m-%d %H:%M:%S"));
+        thread::sleep((next - Local::now()).to_std().unwrap_or_default());
+
+        wait_until_quiet(&save.dir, quiet);
+        backup();
+    }
+}
+
+/// Waits until no file in `dir` has been modified for `quiet`.
+fn wait_until_quiet(dir: &Path, quiet: Duration) {
+    let mut waiting = false;
+    loop {
+        let newest = WalkDir::new(dir)
+            .into_iter()
+            .filter_map(|entry| entry.ok()?.metadata().ok()?.modified().ok())
+            .max();
+        // Modification times in the future count as quiet, rather than waiting for them
+        let since = newest.map_or(quiet, |newest| SystemTime::now().duration_since(newest).unwrap_or(quiet));
+        if since >= quiet {
+            return;
+        }
+        if !waiting {
+            println!("Save changed recently, waiting for the emulator to finish writing...");
+            waiting = true;
+        }
+        thread::sleep(quiet - since);
+    }
+}
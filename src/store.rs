@@ -0,0 +1,365 @@
+//! Content-addressed backup store, an alternative to one zip per backup.
+//!
+//! Every file of a save is split into fixed size chunks that are stored once under their SHA-256 in
+//! `<backup dir>/chunks`. A backup is a small snapshot file next to the zips, holding the manifest
+//! and the chunks of every file, so files that did not change between backups take no extra space.
+//! Chunks no snapshot refers to any more are removed by [`collect_garbage`].
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::archive::{entry_name, EntryInfo, VerifyReport};
+use crate::manifest::{sha256_hex, Manifest, ManifestFile};
+
+/// File extension of snapshot files
+pub const SNAPSHOT_EXTENSION: &str = "snapshot";
+
+/// Folder of the backup directory holding the chunks
+const CHUNKS_DIR: &str = "chunks";
+
+/// Size of the chunks files are split into. Save files that did not change keep all their chunks.
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// Contents of a snapshot file.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Snapshot {
+    pub manifest: Manifest,
+    /// Folders of the save, so empty ones are restored as well
+    #[serde(default)]
+    pub dirs: Vec<String>,
+    /// Chunk hashes of every file in `manifest.files`, by path
+    pub chunks: BTreeMap<String, Vec<String>>,
+}
+
+fn chunks_dir(backup_dir: &Path) -> PathBuf {
+    backup_dir.join(CHUNKS_DIR)
+}
+
+fn chunk_path(backup_dir: &Path, hash: &str) -> PathBuf {
+    chunks_dir(backup_dir).join(&hash[..2]).join(hash)
+}
+
+/// Writes `data` to `path` through a temporary file, so an interrupted write never leaves a partial file.
+fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Stores the files of `source_dir` as chunks and writes a snapshot referring to them to `snapshot_path`.
+///
+/// Like [`crate::archive::create_zip_backup`], the file list of `manifest` is filled in.
+pub fn create_snapshot(source_dir: &Path, snapshot_path: &Path, mut manifest: Manifest) -> Result<Manifest> {
+    let backup_dir = snapshot_path.parent().context("Invalid snapshot path")?;
+    let mut dirs = Vec::new();
+    let mut chunks = BTreeMap::new();
+
+    for entry in WalkDir::new(source_dir).min_depth(1).sort_by_file_name() {
+        let entry = entry?;
+        let name = entry_name(entry.path().strip_prefix(source_dir)?);
+        if entry.file_type().is_dir() {
+            dirs.push(name);
+            continue;
+        }
+
+        let data = fs::read(entry.path())?;
+        let mut hashes = Vec::new();
+        for chunk in data.chunks(CHUNK_SIZE) {
+            let hash = sha256_hex(chunk);
+            let path = chunk_path(backup_dir, &hash);
+            if !path.exists() {
+                fs::create_dir_all(path.parent().unwrap())?;
+                write_atomic(&path, chunk).with_context(|| format!("Failed to store chunk {hash}"))?;
+            }
+            hashes.push(hash);
+        }
+        manifest.files.push(ManifestFile { path: name.clone(), size: data.len() as u64, sha256: sha256_hex(&data) });
+        chunks.insert(name, hashes);
+    }
+
+    let snapshot = Snapshot { manifest, dirs, chunks };
+    write_atomic(snapshot_path, serde_json::to_string_pretty(&snapshot)?.as_bytes())
+        .context("Failed to write snapshot")?;
+    Ok(snapshot.manifest)
+}
+
+/// Reads a snapshot file, checking that every chunk is referred to by a SHA-256 hash.
+pub fn read_snapshot(snapshot_path: &Path) -> Result<Snapshot> {
+    let content = fs::read_to_string(snapshot_path)?;
+    let snapshot: Snapshot =
+        serde_json::from_str(&content).with_context(|| format!("Invalid snapshot {}", snapshot_path.display()))?;
+    if let Some(hash) = snapshot.chunks.values().flatten().find(|hash| !is_sha256_hex(hash)) {
+        bail!("Invalid chunk hash {hash:?} in snapshot {}", snapshot_path.display());
+    }
+    Ok(snapshot)
+}
+
+/// Whether `hash` is a SHA-256 hash as written by [`sha256_hex`]
+fn is_sha256_hex(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+/// Size of every snapshot in `snapshot_paths` together with its chunks, the paths sorted newest first.
+///
+/// A chunk shared by several snapshots is only counted for the newest one referring to it, so deleting
+/// snapshots from the oldest on frees about the size counted for them. Snapshots that cannot be read
+/// are counted without their chunks.
+pub fn snapshot_sizes(backup_dir: &Path, snapshot_paths: &[&Path]) -> Vec<u64> {
+    let mut counted = HashSet::new();
+    snapshot_paths
+        .iter()
+        .map(|path| {
+            let size = fs::metadata(path).map_or(0, |metadata| metadata.len());
+            let Ok(snapshot) = read_snapshot(path) else {
+                return size;
+            };
+            let chunks: u64 = snapshot
+                .chunks
+                .into_values()
+                .flatten()
+                .filter(|hash| counted.insert(hash.clone()))
+                .map(|hash| fs::metadata(chunk_path(backup_dir, &hash)).map_or(0, |metadata| metadata.len()))
+                .sum();
+            size + chunks
+        })
+        .collect()
+}
+
+/// Lists the folders and files of a snapshot, see [`crate::archive::list_entries`].
+pub fn list_snapshot_entries(snapshot_path: &Path) -> Result<Vec<EntryInfo>> {
+    let snapshot = read_snapshot(snapshot_path)?;
+    let dirs = snapshot.dirs.into_iter().map(|name| EntryInfo { name, size: 0, is_dir: true, modified: None });
+    let files = snapshot.manifest.files.into_iter().map(|file| EntryInfo {
+        name: file.path,
+        size: file.size,
+        is_dir: false,
+        modified: None,
+    });
+    let mut entries: Vec<EntryInfo> = dirs.chain(files).collect();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+/// Path of a snapshot entry relative to the restore directory, `None` if it would leave it
+fn relative_path(name: &str) -> Option<PathBuf> {
+    let path = PathBuf::from(name);
+    path.components().all(|c| matches!(c, Component::Normal(_))).then_some(path)
+}
+
+/// Reassembles a file from its chunks, checking every chunk and the whole file against their hashes.
+fn read_file(backup_dir: &Path, file: &ManifestFile, hashes: &[String]) -> Result<Vec<u8>, String> {
+    let mut data = Vec::with_capacity(file.size as usize);
+    for hash in hashes {
+        let mut chunk = Vec::new();
+        File::open(chunk_path(backup_dir, hash))
+            .and_then(|mut f| f.read_to_end(&mut chunk))
+            .map_err(|_| format!("chunk {} missing", &hash[..12]))?;
+        if sha256_hex(&chunk) != *hash {
+            return Err(format!("chunk {} corrupted", &hash[..12]));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    if data.len() as u64 != file.size {
+        return Err(format!("size {} instead of {}", data.len(), file.size));
+    }
+    if sha256_hex(&data) != file.sha256 {
+        return Err("SHA-256 mismatch".to_string());
+    }
+    Ok(data)
+}
+
+/// Checks that every chunk of a snapshot is present and every file matches its hash.
+pub fn verify_snapshot(snapshot_path: &Path) -> Result<VerifyReport> {
+    let backup_dir = snapshot_path.parent().context("Invalid snapshot path")?;
+    let snapshot = read_snapshot(snapshot_path)?;
+    let mut report = VerifyReport { has_manifest: true, ..Default::default() };
+
+    for file in &snapshot.manifest.files {
+        if relative_path(&file.path).is_none() {
+            report.rejected.push((file.path.clone(), "path escapes the target directory".to_string()));
+            continue;
+        }
+        let Some(hashes) = snapshot.chunks.get(&file.path) else {
+            report.missing.push(file.path.clone());
+            continue;
+        };
+        report.checked += 1;
+        if let Err(reason) = read_file(backup_dir, file, hashes) {
+            report.corrupted.push((file.path.clone(), reason));
+        }
+    }
+    Ok(report)
+}
+
+/// Restores the files of a snapshot into `target_dir`.
+///
+/// Files whose chunks are missing or damaged are left out.
+pub fn extract_snapshot(snapshot_path: &Path, target_dir: &Path) -> Result<()> {
+    let backup_dir = snapshot_path.parent().context("Invalid snapshot path")?;
+    let snapshot = read_snapshot(snapshot_path)?;
+    for dir in snapshot.dirs.iter().filter_map(|dir| relative_path(dir)) {
+        fs::create_dir_all(target_dir.join(dir))?;
+    }
+    for file in &snapshot.manifest.files {
+        let (Some(relative), Some(hashes)) = (relative_path(&file.path), snapshot.chunks.get(&file.path)) else {
+            continue;
+        };
+        let Ok(data) = read_file(backup_dir, file, hashes) else {
+            continue;
+        };
+        let path = target_dir.join(relative);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, data)?;
+    }
+    Ok(())
+}
+
+/// Result of a garbage collection run.
+#[derive(Debug, Default)]
+pub struct GcReport {
+    pub removed: usize,
+    pub freed: u64,
+    /// Chunks still referenced by a snapshot
+    pub kept: usize,
+}
+
+/// Deletes the chunks no snapshot in `backup_dir` refers to. With `dry_run` they are only counted.
+///
+/// Nothing is deleted if any snapshot cannot be read, as its chunks would be lost.
+pub fn collect_garbage(backup_dir: &Path, dry_run: bool) -> Result<GcReport> {
+    let chunks_dir = chunks_dir(backup_dir);
+    let mut report = GcReport::default();
+    if !chunks_dir.exists() {
+        return Ok(report);
+    }
+
+    let mut referenced = HashSet::new();
+    for entry in fs::read_dir(backup_dir).context("Failed to read backup directory")? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == SNAPSHOT_EXTENSION) {
+            let snapshot = read_snapshot(&path).context("Refusing to remove chunks")?;
+            referenced.extend(snapshot.chunks.into_values().flatten());
+        }
+    }
+
+    for entry in WalkDir::new(&chunks_dir).min_depth(2).max_depth(2) {
+        let entry = entry?;
+        let hash = entry.file_name().to_string_lossy();
+        if referenced.contains(hash.as_ref()) {
+            report.kept += 1;
+            continue;
+        }
+        report.removed += 1;
+        report.freed += entry.metadata()?.len();
+        if !dry_run {
+            fs::remove_file(entry.path())
+                .with_context(|| format!("Failed to delete {}", entry.path().display()))?;
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Emulator;
+    use crate::manifest::MANIFEST_VERSION;
+
+    /// Bytes that do not repeat within a chunk, so every chunk of a file is different
+    fn data(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn manifest(source_dir: &Path) -> Manifest {
+        Manifest {
+            format_version: MANIFEST_VERSION,
+            name: "test".to_string(),
+            created: chrono::Local::now().fixed_offset(),
+            source_dir: source_dir.to_path_buf(),
+            emulator: Emulator::Yuzu,
+            user_id: None,
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            files: Vec::new(),
+        }
+    }
+
+    /// Hashes of the chunks in the store
+    fn stored_chunks(backup_dir: &Path) -> HashSet<String> {
+        WalkDir::new(chunks_dir(backup_dir))
+            .min_depth(2)
+            .max_depth(2)
+            .into_iter()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn referenced_chunks(snapshot_path: &Path) -> HashSet<String> {
+        read_snapshot(snapshot_path).unwrap().chunks.into_values().flatten().collect()
+    }
+
+    #[test]
+    fn garbage_collection_keeps_chunks_of_remaining_snapshots() {
+        let root = std::env::temp_dir().join(format!("acnh-backup-test-store-gc-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let (save, backups) = (root.join("save"), root.join("backups"));
+        fs::create_dir_all(save.join("Villager0")).unwrap();
+        fs::create_dir_all(save.join("Villager1")).unwrap();
+        fs::create_dir_all(&backups).unwrap();
+
+        // The second snapshot changes the last chunk of main.dat and one player, and adds a file
+        let mut main = data(CHUNK_SIZE * 2 + 1000, 1);
+        fs::write(save.join("main.dat"), &main).unwrap();
+        fs::write(save.join("Villager0/personal.dat"), data(5000, 2)).unwrap();
+        let first = backups.join("first.snapshot");
+        create_snapshot(&save, &first, manifest(&save)).unwrap();
+
+        main[CHUNK_SIZE * 2] ^= 0xFF;
+        fs::write(save.join("main.dat"), &main).unwrap();
+        fs::write(save.join("Villager0/personal.dat"), data(5000, 3)).unwrap();
+        fs::write(save.join("landname.dat"), data(100, 4)).unwrap();
+        let second = backups.join("second.snapshot");
+        create_snapshot(&save, &second, manifest(&save)).unwrap();
+
+        let (first_chunks, second_chunks) = (referenced_chunks(&first), referenced_chunks(&second));
+        assert_eq!(first_chunks.intersection(&second_chunks).count(), 2, "the first two chunks of main.dat are shared");
+        assert_eq!(stored_chunks(&backups), &first_chunks | &second_chunks);
+
+        // Nothing is unused while both snapshots exist
+        let report = collect_garbage(&backups, false).unwrap();
+        assert_eq!((report.removed, report.kept), (0, first_chunks.len() + second_chunks.len() - 2));
+
+        fs::remove_file(&first).unwrap();
+        let dry_run = collect_garbage(&backups, true).unwrap();
+        assert_eq!(dry_run.removed, first_chunks.difference(&second_chunks).count());
+        assert_eq!(stored_chunks(&backups), &first_chunks | &second_chunks);
+        let report = collect_garbage(&backups, false).unwrap();
+        assert_eq!((report.removed, report.kept), (dry_run.removed, second_chunks.len()));
+        assert_eq!(stored_chunks(&backups), second_chunks);
+
+        assert!(verify_snapshot(&second).unwrap().is_ok());
+        let restored = root.join("restored");
+        extract_snapshot(&second, &restored).unwrap();
+        for name in ["main.dat", "Villager0/personal.dat", "landname.dat"] {
+            assert_eq!(fs::read(restored.join(name)).unwrap(), fs::read(save.join(name)).unwrap(), "{name}");
+        }
+        assert!(restored.join("Villager1").is_dir(), "empty folders are restored");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
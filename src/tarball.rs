@@ -0,0 +1,286 @@
+//! Backups as zstd compressed tar archives (`.tar.zst`).
+//!
+//! Unlike in zip backups the manifest is the first entry, so it can be read without decompressing
+//! the whole archive.
+
+use anyhow::{bail, Context, Result};
+use chrono::{Local, TimeZone};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::archive::{check_entry_name, entry_name, EntryInfo, VerifyReport};
+use crate::manifest::{sha256_hex, Manifest, ManifestFile, MANIFEST_NAME};
+
+type TarReader = tar::Archive<zstd::Decoder<'static, io::BufReader<File>>>;
+
+fn open(backup_path: &Path) -> Result<TarReader> {
+    let file = File::open(backup_path)?;
+    Ok(tar::Archive::new(zstd::Decoder::new(file)?))
+}
+
+/// Writes the contents of `source_dir` to a tar archive compressed with zstd at `level`.
+///
+/// The file list of `manifest` is filled with the size and SHA-256 of every file, and the manifest
+/// is stored as the first entry of the archive.
+pub fn create_tar_backup(
+    source_dir: &Path,
+    backup_path: &Path,
+    mut manifest: Manifest,
+    level: Option<i64>,
+) -> Result<Manifest> {
+    // The files are read up front, the manifest has to be written before them
+    let mut entries = Vec::new();
+    for entry in WalkDir::new(source_dir).min_depth(1).sort_by_file_name() {
+        let entry = entry?;
+        let name = entry_name(entry.path().strip_prefix(source_dir)?);
+        let data = if entry.file_type().is_dir() {
+            Vec::new()
+        } else {
+            let data = fs::read(entry.path())?;
+            manifest.files.push(ManifestFile { path: name.clone(), size: data.len() as u64, sha256: sha256_hex(&data) });
+            data
+        };
+        entries.push((name, entry.metadata()?, data));
+    }
+
+    let level = i32::try_from(level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL.into()))
+        .context("Unsupported compression level")?;
+    let encoder = zstd::Encoder::new(File::create(backup_path)?, level)?;
+    let mut tar = tar::Builder::new(encoder);
+
+    let manifest_json = serde_json::to_string_pretty(&manifest)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest_json.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(manifest.created.timestamp().max(0) as u64);
+    tar.append_data(&mut header, MANIFEST_NAME, manifest_json.as_bytes())?;
+
+    for (name, metadata, data) in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(&metadata);
+        header.set_size(data.len() as u64);
+        tar.append_data(&mut header, name, data.as_slice())?;
+    }
+
+    tar.into_inner()?.finish()?;
+    Ok(manifest)
+}
+
+/// Reads the manifest of a tar backup, `None` if it has none.
+pub fn read_tar_manifest(backup_path: &Path) -> Result<Option<Manifest>> {
+    let mut archive = open(backup_path)?;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.as_ref() != Path::new(MANIFEST_NAME) {
+            continue;
+        }
+        let mut content = String::new();
+        entry.read_to_string(&mut content)?;
+        let manifest = serde_json::from_str(&content)
+            .with_context(|| format!("Invalid {} in {}", MANIFEST_NAME, backup_path.display()))?;
+        return Ok(Some(manifest));
+    }
+    Ok(None)
+}
+
+/// Lists the entries of a tar backup, see [`crate::archive::list_entries`].
+pub fn list_tar_entries(backup_path: &Path) -> Result<Vec<EntryInfo>> {
+    let mut archive = open(backup_path)?;
+    let mut entries = Vec::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let header = entry.header();
+        let modified = header
+            .mtime()
+            .ok()
+            .and_then(|mtime| Local.timestamp_opt(mtime as i64, 0).single())
+            .map(|time| time.naive_local());
+        entries.push(EntryInfo {
+            name: entry_name(&entry.path()?),
+            size: header.size()?,
+            is_dir: header.entry_type().is_dir(),
+            modified,
+        });
+    }
+    Ok(entries)
+}
+
+/// Path of a tar entry relative to the extraction directory, or why the entry must not be extracted.
+/// Only plain files and folders inside the extraction directory are accepted, see
+/// [`check_entry_name`].
+fn entry_path<R: Read>(entry: &tar::Entry<'_, R>) -> Result<PathBuf, &'static str> {
+    let entry_type = entry.header().entry_type();
+    if entry_type.is_symlink() || entry_type.is_hard_link() {
+        return Err("link");
+    }
+    if !entry_type.is_file() && !entry_type.is_dir() {
+        return Err("unsupported entry type");
+    }
+    check_entry_name(&String::from_utf8_lossy(&entry.path_bytes()))?;
+    let path = entry.path().map_err(|_| "invalid path")?;
+    if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err("path escapes the target directory");
+    }
+    Ok(path.into_owned())
+}
+
+/// Reads every entry of a tar backup and compares it with the size and SHA-256 recorded in the manifest.
+pub fn verify_tar_backup(backup_path: &Path) -> Result<VerifyReport> {
+    let manifest = read_tar_manifest(backup_path)?;
+    let mut expected: BTreeMap<String, _> = manifest
+        .iter()
+        .flat_map(|manifest| &manifest.files)
+        .map(|file| (file.path.clone(), file))
+        .collect();
+    let mut report = VerifyReport { has_manifest: manifest.is_some(), ..Default::default() };
+
+    let mut archive = open(backup_path)?;
+    for entry in archive.entries()? {
+        let mut entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                // The rest of the stream cannot be read after a broken entry
+                report.corrupted.push(("archive".to_string(), e.to_string()));
+                break;
+            }
+        };
+        let name = entry_name(&entry.path()?);
+        let relative = match entry_path(&entry) {
+            Ok(relative) => relative,
+            Err(reason) => {
+                report.rejected.push((name, reason.to_string()));
+                continue;
+            }
+        };
+        if entry.header().entry_type().is_dir() || relative == Path::new(MANIFEST_NAME) {
+            continue;
+        }
+        report.checked += 1;
+
+        let mut data = Vec::new();
+        if let Err(e) = entry.read_to_end(&mut data) {
+            report.corrupted.push((name, e.to_string()));
+            break;
+        }
+        if manifest.is_some() {
+            report.check_file(&mut expected, name, &data);
+        }
+    }
+
+    report.missing.extend(expected.into_keys());
+    Ok(report)
+}
+
+/// Extracts the save files of a tar backup into `target_dir`, leaving out the manifest.
+///
+/// Entries that would end up outside `target_dir` are reported and skipped.
+pub fn extract_tar_backup(backup_path: &Path, target_dir: &Path) -> Result<()> {
+    let mut archive = open(backup_path)?;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let outpath = match entry_path(&entry) {
+            Ok(relative) if relative == Path::new(MANIFEST_NAME) => continue,
+            Ok(relative) => target_dir.join(relative),
+            Err(reason) => {
+                println!("Skipping unsafe entry {} ({reason})", entry.path()?.display());
+                continue;
+            }
+        };
+
+        if entry.header().entry_type().is_dir() {
+            fs::create_dir_all(&outpath)?;
+        } else {
+            if let Some(p) = outpath.parent() {
+                fs::create_dir_all(p)?;
+            }
+            io::copy(&mut entry, &mut File::create(&outpath)?)?;
+        }
+
+        // Set permissions, without setuid, setgid or sticky bits
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if let Ok(mode) = entry.header().mode() {
+                fs::set_permissions(&outpath, fs::Permissions::from_mode(mode & 0o777))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that every file in the manifest of a tar backup was extracted to `dir` with the expected size.
+pub fn verify_tar_extracted(backup_path: &Path, dir: &Path) -> Result<()> {
+    let Some(manifest) = read_tar_manifest(backup_path)? else {
+        return Ok(());
+    };
+    for file in &manifest.files {
+        let size = fs::metadata(dir.join(&file.path))
+            .with_context(|| format!("{} missing after extraction", file.path))?
+            .len();
+        if size != file.size {
+            bail!("{} has size {} after extraction, expected {}", file.path, size, file.size);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::extract_backup;
+    use std::io::Write;
+
+    /// Appends an entry with `name` written to the header as is, the tar builder refuses unsafe paths
+    fn append_raw<W: Write>(tar: &mut tar::Builder<W>, name: &str, entry_type: tar::EntryType, link: &str, data: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+        header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+        header.set_entry_type(entry_type);
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append(&header, data).unwrap();
+    }
+
+    #[test]
+    fn tar_unsafe_entries_are_rejected() {
+        let root = std::env::temp_dir().join(format!("acnh-backup-test-tar-unsafe-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let outside = root.join("outside").to_string_lossy().into_owned();
+        let backup = root.join("backup.tar.zst");
+
+        let mut tar = tar::Builder::new(zstd::Encoder::new(File::create(&backup).unwrap(), 0).unwrap());
+        append_raw(&mut tar, "main.dat", tar::EntryType::Regular, "", b"save");
+        let unsafe_files = ["../outside", "Villager0/../../outside", &outside, "C:/outside", "..\\outside"];
+        for name in unsafe_files {
+            append_raw(&mut tar, name, tar::EntryType::Regular, "", b"evil");
+        }
+        append_raw(&mut tar, "link", tar::EntryType::Symlink, &outside, b"");
+        append_raw(&mut tar, "hardlink", tar::EntryType::Link, &outside, b"");
+        tar.into_inner().unwrap().finish().unwrap();
+
+        let report = verify_tar_backup(&backup).unwrap();
+        assert_eq!(report.rejected.len(), unsafe_files.len() + 2, "{report}");
+        assert_eq!(report.checked, 1);
+
+        let target = root.join("target");
+        fs::create_dir_all(&target).unwrap();
+        assert!(extract_backup(&backup, &target, false, None).is_err());
+        extract_backup(&backup, &target, true, None).unwrap();
+        let written: Vec<String> = WalkDir::new(&root)
+            .min_depth(1)
+            .sort_by_file_name()
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| !entry.file_type().is_dir())
+            .map(|entry| entry_name(entry.path().strip_prefix(&root).unwrap()))
+            .collect();
+        assert_eq!(written, ["backup.tar.zst", "target/main.dat"]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,68 @@
+//! Watch mode: backs up the save folder automatically whenever the emulator has written to it.
+
+use anyhow::{bail, Context, Result};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use crate::emulator::SaveLocation;
+
+/// Watches the save folder and runs `backup` once it has been quiet for `quiet` after a change.
+///
+/// The parent folder is watched rather than the save folder itself, so the watch survives the
+/// save folder being replaced by a restore. Runs until the process is stopped.
+///
+/// The `if_running` setting is deliberately not applied: the emulator runs the whole time the game
+/// is played, and waiting for `quiet` already keeps backups from catching the emulator mid-write.
+pub fn watch(save: &SaveLocation, quiet: Duration, mut backup: impl FnMut()) -> Result<()> {
+    let watched_dir = save
+        .dir
+        .parent()
+        .with_context(|| format!("Invalid save directory {}", save.dir.display()))?;
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher
+        .watch(watched_dir, RecursiveMode::Recursive)
+        .with_context(|| format!("Failed to watch {}", watched_dir.display()))?;
+
+    println!("Watching {} for changes, press Ctrl+C to stop", save.dir.display());
+    loop {
+        let event = rx.recv().context("File watcher stopped")?;
+        if !is_save_change(event, &save.dir) {
+            continue;
+        }
+
+        // The emulator writes several files per save, wait until it is done
+        println!("Save changed, waiting for the emulator to finish writing...");
+        let mut deadline = Instant::now() + quiet;
+        loop {
+            match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(event) => {
+                    if is_save_change(event, &save.dir) {
+                        deadline = Instant::now() + quiet;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => bail!("File watcher stopped"),
+            }
+        }
+
+        backup();
+    }
+}
+
+/// Whether `event` is a change of a file inside `save_dir`. Errors of the watcher are reported and ignored.
+fn is_save_change(event: notify::Result<Event>, save_dir: &Path) -> bool {
+    match event {
+        Ok(event) => {
+            matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_))
+                && event.paths.iter().any(|path| path.starts_with(save_dir))
+        }
+        Err(e) => {
+            println!("Warning: file watcher error: {e}");
+            false
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Reading the save folders and user profiles of Yuzu and its forks (Suyu, Sudachi).
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::emulator::Profile;
+
+/// Size of the header in front of the user entries of `profiles.dat`
+const PROFILES_HEADER_SIZE: usize = 0x10;
+/// Size of one user entry: uuid, uuid2, timestamp, username and extra data
+const PROFILE_ENTRY_SIZE: usize = 0xC8;
+/// Offset and size of the username inside a user entry
+const USERNAME_OFFSET: usize = 0x28;
+const USERNAME_SIZE: usize = 0x20;
+
+/// A game save found in the Yuzu save directory.
+#[derive(Debug, Clone)]
+pub struct SaveData {
+    pub title_id: u64,
+    pub user_id: u128,
+    pub path: PathBuf,
+}
+
+/// Directory holding the per-user saves of a Yuzu installation
+pub fn user_save_dir(yuzu_dir: &Path) -> PathBuf {
+    yuzu_dir.join("nand").join("user").join("save").join("0000000000000000")
+}
+
+/// Finds all user saves, which are stored in `nand/user/save/0000000000000000/<user id>/<title id>`.
+pub fn find_saves(yuzu_dir: &Path) -> Result<Vec<SaveData>> {
+    let save_dir = user_save_dir(yuzu_dir);
+    if !save_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut saves = Vec::new();
+    for user in fs::read_dir(&save_dir).with_context(|| format!("Failed to read {}", save_dir.display()))? {
+        let user = user?.path();
+        let Some(user_id) = parse_hex_name(&user, 32).and_then(|id| u128::from_str_radix(&id, 16).ok()) else {
+            continue;
+        };
+        for title in fs::read_dir(&user)? {
+            let path = title?.path();
+            let Some(title_id) = parse_hex_name(&path, 16).and_then(|id| u64::from_str_radix(&id, 16).ok()) else {
+                continue;
+            };
+            saves.push(SaveData { title_id, user_id, path });
+        }
+    }
+    saves.sort_by_key(|save| (save.user_id, save.title_id));
+    Ok(saves)
+}
+
+/// Returns the file name of `path` if it is a directory named by `len` hex digits.
+fn parse_hex_name(path: &Path, len: usize) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    (path.is_dir() && name.len() == len && name.chars().all(|c| c.is_ascii_hexdigit())).then(|| name.to_string())
+}
+
+/// The user a save folder belongs to, taken from the name of its parent folder.
+pub fn read_save_user(save_dir: &Path) -> Option<u128> {
+    let user = save_dir.parent()?;
+    parse_hex_name(user, 32).and_then(|id| u128::from_str_radix(&id, 16).ok())
+}
+
+/// Reads the user profiles from the account system save (`su/avators/profiles.dat`).
+///
+/// The file holds 8 fixed size user entries, unused entries have a zero uuid. A uuid is stored as
+/// two little endian u64, low half first, and shown high half first like the save folder names.
+pub fn read_profiles(yuzu_dir: &Path) -> Result<Vec<Profile>> {
+    let path = yuzu_dir
+        .join("nand")
+        .join("system")
+        .join("save")
+        .join("8000000000000010")
+        .join("su")
+        .join("avators")
+        .join("profiles.dat");
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let data = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+
+    let profiles = data
+        .get(PROFILES_HEADER_SIZE..)
+        .unwrap_or_default()
+        .chunks_exact(PROFILE_ENTRY_SIZE)
+        .filter_map(|entry| {
+            let low = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+            let high = u64::from_le_bytes(entry[8..16].try_into().unwrap());
+            let user_id = ((high as u128) << 64) | low as u128;
+            if user_id == 0 {
+                return None;
+            }
+            let name = &entry[USERNAME_OFFSET..USERNAME_OFFSET + USERNAME_SIZE];
+            let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            Some(Profile { user_id, name: String::from_utf8_lossy(&name[..end]).into_owned() })
+        })
+        .collect();
+    Ok(profiles)
+}